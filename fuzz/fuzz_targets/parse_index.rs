@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+// Any input must give an error rather than a panic or a huge allocation.
+fuzz_target!(|data: &[u8]| {
+    let mut stm = Cursor::new(data);
+    if let Ok(head_info) = mabi_pack::read_header(&mut stm) {
+        let _ = mabi_pack::read_index(&mut stm, &head_info);
+    }
+});
@@ -0,0 +1,238 @@
+use super::extract::{read_file_content_with, read_file_range, ExtractOptions};
+use super::{
+    normalize_name, open_pack, read_header_with, read_index_with, FileInfo, HeadInfo, MabiError,
+    ReadOptions,
+};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+
+/// The header and the index of a pack, without a handle to its content. See
+/// `Pack::open_index_only`.
+pub struct PackIndex {
+    head_info: HeadInfo,
+    entries: Vec<FileInfo>,
+}
+
+impl PackIndex {
+    pub fn head_info(&self) -> &HeadInfo {
+        &self.head_info
+    }
+
+    pub fn entries(&self) -> &[FileInfo] {
+        &self.entries
+    }
+
+    /// Finds a file by name, like `Pack::find`.
+    pub fn find(&self, name: &str) -> Option<&FileInfo> {
+        find_entry(&self.entries, name).map(|i| &self.entries[i])
+    }
+}
+
+// The position of the last entry with this name, compared like the game does.
+fn find_entry(entries: &[FileInfo], name: &str) -> Option<usize> {
+    let name = normalize_name(name);
+    entries
+        .iter()
+        .rposition(|e| normalize_name(&e.name) == name)
+}
+
+/// How well the cache of a `Pack` does, to tune its capacity.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads served from the cache.
+    pub hits: u64,
+    /// Reads which decoded the file.
+    pub misses: u64,
+    /// The number of files cached.
+    pub entries: usize,
+    /// Their total decoded size in bytes.
+    pub size: u64,
+}
+
+// The decoded content of recently read files by entry, evicting the least
+// recently used ones past `capacity` bytes.
+#[derive(Default)]
+struct Cache {
+    capacity: u64,
+    files: HashMap<usize, (Vec<u8>, u64)>,
+    // incremented on every use, the second field of `files` being the last one
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl Cache {
+    fn get(&mut self, i: usize) -> Option<&[u8]> {
+        self.clock += 1;
+        match self.files.get_mut(&i) {
+            Some((content, used)) => {
+                *used = self.clock;
+                self.stats.hits += 1;
+                Some(content)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, i: usize, content: &[u8]) {
+        let len = content.len() as u64;
+        if len > self.capacity {
+            return;
+        }
+        while self.stats.size + len > self.capacity {
+            let oldest = self
+                .files
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(&i, _)| i)
+                .unwrap();
+            let (evicted, _) = self.files.remove(&oldest).unwrap();
+            self.stats.size -= evicted.len() as u64;
+        }
+        self.files.insert(i, (content.to_vec(), self.clock));
+        self.stats.size += len;
+        self.stats.entries = self.files.len();
+    }
+
+    fn set_capacity(&mut self, capacity: u64) {
+        self.capacity = capacity;
+        self.files.clear();
+        self.stats = CacheStats::default();
+    }
+}
+
+/// An opened pack, to read its files by name.
+pub struct Pack {
+    reader: BufReader<File>,
+    head_info: HeadInfo,
+    entries: Vec<FileInfo>,
+    options: ReadOptions,
+    cache: Cache,
+}
+
+impl Pack {
+    pub fn open(path: &str) -> Result<Pack, MabiError> {
+        Pack::open_with(path, &ReadOptions::default())
+    }
+
+    pub fn open_with(path: &str, options: &ReadOptions) -> Result<Pack, MabiError> {
+        let fs = open_pack(path)?;
+        let mut reader = BufReader::new(fs);
+        let head_info = read_header_with(&mut reader, options)?;
+        let entries = read_index_with(&mut reader, &head_info, options)?;
+        Ok(Pack {
+            reader,
+            head_info,
+            entries,
+            options: options.clone(),
+            cache: Cache::default(),
+        })
+    }
+
+    /// Keeps the decoded content of the files read last, up to `capacity`
+    /// bytes in all, to return them again without decoding them, e.g. for a
+    /// virtual filesystem reading the same files over and over. Files larger
+    /// than that are never cached. 0, the default, disables the cache. Clears
+    /// the cache and its stats.
+    pub fn set_cache_capacity(&mut self, capacity: u64) {
+        self.cache.set_capacity(capacity);
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats
+    }
+
+    /// Reads only the header and the index of a pack, e.g. to serve the
+    /// metadata of many packs. Only the first `header_size + index_size` bytes
+    /// of the file are read: its length is queried (seeking to its end) to
+    /// check the index, but the content is never read.
+    pub fn open_index_only(path: &str) -> Result<PackIndex, MabiError> {
+        Pack::open_index_only_with(path, &ReadOptions::default())
+    }
+
+    pub fn open_index_only_with(path: &str, options: &ReadOptions) -> Result<PackIndex, MabiError> {
+        // unbuffered, as a buffer would read ahead past the index
+        let mut fs = open_pack(path)?;
+        let head_info = read_header_with(&mut fs, options)?;
+        let entries = read_index_with(&mut fs, &head_info, options)?;
+        Ok(PackIndex { head_info, entries })
+    }
+
+    pub fn head_info(&self) -> &HeadInfo {
+        &self.head_info
+    }
+
+    pub fn entries(&self) -> &[FileInfo] {
+        &self.entries
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        find_entry(&self.entries, name)
+    }
+
+    /// Finds a file by name, compared case-insensitively like the game does, and
+    /// with `/` as the separator too.
+    /// If the pack holds several files with this name, the last one is returned.
+    pub fn find(&self, name: &str) -> Option<&FileInfo> {
+        self.position(name).map(|i| &self.entries[i])
+    }
+
+    fn entry_index(&self, name: &str) -> Result<usize, MabiError> {
+        self.position(name)
+            .ok_or_else(|| MabiError::NotFound(name.to_string()))
+    }
+
+    /// Reads the whole content of a file, from the cache if it's there.
+    pub fn read(&mut self, name: &str) -> Result<Vec<u8>, MabiError> {
+        let i = self.entry_index(name)?;
+        if self.cache.capacity > 0 {
+            if let Some(content) = self.cache.get(i) {
+                return Ok(content.to_vec());
+            }
+        }
+        let options = ExtractOptions {
+            read_options: self.options.clone(),
+            ..Default::default()
+        };
+        let content = read_file_content_with(
+            &mut self.reader,
+            &self.head_info,
+            &self.entries[i],
+            &options,
+        )?;
+        if self.cache.capacity > 0 {
+            self.cache.insert(i, &content);
+        }
+        Ok(content)
+    }
+
+    /// Reads `len` bytes of a file from `start`, e.g. the header of a large
+    /// asset. The content is still decoded from the beginning, but only up to
+    /// `start + len`. Returns less than `len` bytes at the end of the file.
+    pub fn extract_range(
+        &mut self,
+        name: &str,
+        start: u64,
+        len: u64,
+    ) -> Result<Vec<u8>, MabiError> {
+        let i = self.entry_index(name)?;
+        if self.cache.capacity > 0 {
+            if let Some(content) = self.cache.get(i) {
+                let start = (start as usize).min(content.len());
+                let end = start.saturating_add(len as usize).min(content.len());
+                return Ok(content[start..end].to_vec());
+            }
+        }
+        read_file_range(
+            &mut self.reader,
+            &self.head_info,
+            &self.entries[i],
+            start,
+            len,
+            &self.options,
+        )
+    }
+}
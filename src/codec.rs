@@ -0,0 +1,140 @@
+use super::keystream;
+use super::{Compression, KeyGenerator, MabiError};
+use libflate::{deflate, gzip, zlib};
+use std::io::{self, Cursor, Read, Write};
+
+pub(crate) fn compress(stm: &[u8], compression: Compression) -> Result<Vec<u8>, MabiError> {
+    let map_err = |e: std::io::Error| MabiError::CompressError(e.to_string());
+    match compression {
+        Compression::Zlib => {
+            let mut encoder = zlib::Encoder::new(vec![]).map_err(map_err)?;
+            encoder.write_all(stm).map_err(map_err)?;
+            encoder.finish().into_result().map_err(map_err)
+        }
+        Compression::Gzip => {
+            let mut encoder = gzip::Encoder::new(vec![]).map_err(map_err)?;
+            encoder.write_all(stm).map_err(map_err)?;
+            encoder.finish().into_result().map_err(map_err)
+        }
+        Compression::RawDeflate => {
+            let mut encoder = deflate::Encoder::new(vec![]);
+            encoder.write_all(stm).map_err(map_err)?;
+            encoder.finish().into_result().map_err(map_err)
+        }
+        Compression::Stored => Ok(stm.to_vec()),
+    }
+}
+
+/// Decompresses what is read from `stm`, pulling from it only as much as the
+/// output read needs.
+pub(crate) fn make_decoder<'a>(
+    stm: impl Read + 'a,
+    compression: Compression,
+) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        Compression::Zlib => Box::new(zlib::Decoder::new(stm)?),
+        Compression::Gzip => Box::new(gzip::Decoder::new(stm)?),
+        Compression::RawDeflate => Box::new(deflate::Decoder::new(stm)),
+        Compression::Stored => Box::new(stm),
+    })
+}
+
+// The checks zlib::Decoder does on the first two bytes: deflate as method, and
+// a check value making them a multiple of 31.
+pub(crate) fn has_zlib_header(buff: &[u8]) -> bool {
+    buff.len() >= 2
+        && buff[0] & 0x0f == 8
+        && (buff[0] as u16 * 256 + buff[1] as u16).is_multiple_of(31)
+}
+
+/// Decompresses a decrypted entry, decoding at most `limit` bytes.
+pub(crate) fn decompress(
+    buff: Vec<u8>,
+    compression: Compression,
+    limit: u64,
+) -> Result<Vec<u8>, MabiError> {
+    let mut decoded_buff = vec![];
+    make_decoder(Cursor::new(buff), compression)?
+        .take(limit)
+        .read_to_end(&mut decoded_buff)?;
+    Ok(decoded_buff)
+}
+
+/// Compresses a buffer with zlib and encrypts it with the key stream of `key`,
+/// giving the content of an entry of that version as the game stores it.
+pub fn encode_entry(data: &[u8], key: u32) -> Result<Vec<u8>, MabiError> {
+    encode_entry_with(data, key, Compression::Zlib, KeyGenerator::default())
+}
+
+/// Like `encode_entry`, with another compression or key generator.
+pub fn encode_entry_with(
+    data: &[u8],
+    key: u32,
+    compression: Compression,
+    key_generator: KeyGenerator,
+) -> Result<Vec<u8>, MabiError> {
+    encode_entry_seeded(data, keystream::seed(key), compression, key_generator)
+}
+
+// Like `encode_entry_with`, with the seed of the key stream given.
+pub(crate) fn encode_entry_seeded(
+    data: &[u8],
+    seed: u32,
+    compression: Compression,
+    key_generator: KeyGenerator,
+) -> Result<Vec<u8>, MabiError> {
+    let mut encoded_buff = compress(data, compression)?;
+    keystream::xor_seeded(&mut encoded_buff, seed, key_generator);
+    Ok(encoded_buff)
+}
+
+/// Decrypts and decompresses the content of a zlib entry of this version, the
+/// reverse of `encode_entry`. With `expected_len`, fails with `CorruptedFile`
+/// unless it decodes to exactly that many bytes, without decoding more.
+pub fn decode_entry(
+    raw: &[u8],
+    version: u32,
+    expected_len: Option<u32>,
+) -> Result<Vec<u8>, MabiError> {
+    decode_entry_with(
+        raw,
+        version,
+        expected_len,
+        Compression::Zlib,
+        KeyGenerator::default(),
+    )
+}
+
+/// Like `decode_entry`, with another compression or key generator.
+pub fn decode_entry_with(
+    raw: &[u8],
+    version: u32,
+    expected_len: Option<u32>,
+    compression: Compression,
+    key_generator: KeyGenerator,
+) -> Result<Vec<u8>, MabiError> {
+    let seed = keystream::seed(version);
+    decode_entry_seeded(raw, seed, expected_len, compression, key_generator)
+}
+
+// Like `decode_entry_with`, with the seed of the key stream given.
+pub(crate) fn decode_entry_seeded(
+    raw: &[u8],
+    seed: u32,
+    expected_len: Option<u32>,
+    compression: Compression,
+    key_generator: KeyGenerator,
+) -> Result<Vec<u8>, MabiError> {
+    // like an entry with no content in a pack
+    if raw.is_empty() && expected_len.unwrap_or(0) == 0 {
+        return Ok(vec![]);
+    }
+    let mut buff = raw.to_vec();
+    keystream::xor_seeded(&mut buff, seed, key_generator);
+    let limit = expected_len.map_or(u64::MAX, |len| len as u64 + 1);
+    let decoded_buff = decompress(buff, compression, limit)?;
+    match expected_len {
+        Some(len) if decoded_buff.len() != len as usize => Err(MabiError::CorruptedFile),
+        _ => Ok(decoded_buff),
+    }
+}
@@ -0,0 +1,185 @@
+use super::json::Object;
+use super::keystream;
+use super::pack::{calc_str_size, ROOT_NAME_SIZE};
+use super::trailer::TRAILER_SIZE;
+use super::{parse_version, Compression, KeyGenerator, MabiError, HEADER_SIZE};
+use std::io::{self, Write};
+
+// (name, offset, size, description) of the header fields
+const HEADER_FIELDS: &[(&str, u64, u64, &str)] = &[
+    ("magic", 0x0, 4, "PACK"),
+    ("pack_version", 0x4, 4, "0x102"),
+    ("file_version", 0x8, 4, "the version of the pack"),
+    ("file_count", 0xc, 4, "the number of entries"),
+    ("created", 0x10, 8, "FILETIME"),
+    ("modified", 0x18, 8, "FILETIME"),
+    (
+        "root_name",
+        0x20,
+        ROOT_NAME_SIZE as u64,
+        "NUL-terminated, e.g. data\\",
+    ),
+    ("file_count2", 0x200, 4, "same as file_count"),
+    ("index_size", 0x204, 4, "the size of the index in bytes"),
+    ("unknown", 0x208, 4, "0"),
+    ("content_size", 0x20c, 4, "the size of the content in bytes"),
+    ("reserved", 0x210, 0x10, "0"),
+];
+
+// (name, offset, size, description) of the fields after the string block
+const ENTRY_FIELDS: &[(&str, u64, u64, &str)] = &[
+    ("version", 0x0, 4, "also the seed of the key stream"),
+    ("unknown", 0x4, 4, "0"),
+    ("offset", 0x8, 4, "from the start of the content"),
+    ("raw_size", 0xc, 4, "the stored size"),
+    ("uncompressed_size", 0x10, 4, "the size once decoded"),
+    ("compression", 0x14, 4, "see compression_flags"),
+    ("created", 0x18, 8, "FILETIME"),
+    ("created2", 0x20, 8, "FILETIME, same as created"),
+    ("accessed", 0x28, 8, "FILETIME"),
+    ("modified", 0x30, 8, "FILETIME"),
+    ("modified2", 0x38, 8, "FILETIME, same as modified"),
+];
+
+const ENTRY_FIELDS_SIZE: u64 = 0x40;
+
+fn write_fields(out: &mut impl Write, fields: &[(&str, u64, u64, &str)]) -> io::Result<()> {
+    for (i, (name, offset, size, description)) in fields.iter().enumerate() {
+        let field = Object::new()
+            .string("name", name)
+            .value("offset", offset)
+            .value("size", size)
+            .string("description", description);
+        writeln!(
+            out,
+            "      {}{}",
+            field,
+            if i + 1 < fields.len() { "," } else { "" }
+        )?;
+    }
+    Ok(())
+}
+
+// The ranges of name lengths sharing a marker, from what `pack` writes. The
+// block size of marker 5 grows with the name, so only its first is kept.
+fn string_block_rules() -> Vec<(usize, usize, usize, u8)> {
+    let mut rules: Vec<(usize, usize, usize, u8)> = vec![];
+    for len in 0..0x100 {
+        let (size, marker) = calc_str_size(len);
+        match rules.last_mut() {
+            Some(rule) if rule.3 == marker => rule.1 = len,
+            _ => rules.push((len, len, size, marker)),
+        }
+    }
+    rules
+}
+
+/// Prints the layout of a pack as this crate reads and writes it, as JSON.
+pub fn run_describe_format() -> Result<(), MabiError> {
+    let mut out = io::stdout().lock();
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"endianness\": \"little\",")?;
+    writeln!(out, "  \"header\": {{")?;
+    writeln!(out, "    \"offset\": 0,")?;
+    writeln!(out, "    \"size\": {},", HEADER_SIZE)?;
+    writeln!(out, "    \"fields\": [")?;
+    write_fields(&mut out, HEADER_FIELDS)?;
+    writeln!(out, "    ]")?;
+    writeln!(out, "  }},")?;
+    writeln!(out, "  \"index\": {{")?;
+    writeln!(out, "    \"offset\": {},", HEADER_SIZE)?;
+    writeln!(
+        out,
+        "    \"entry\": \"a string block holding the name, then {} bytes of fields\",",
+        ENTRY_FIELDS_SIZE
+    )?;
+    writeln!(out, "    \"string_block\": {{")?;
+    writeln!(
+        out,
+        "      \"layout\": \"a marker byte, a u32 length for marker 5, the name, then zeros up to block_size\","
+    )?;
+    writeln!(out, "      \"rules\": [")?;
+    let rules = string_block_rules();
+    for (i, (min_len, max_len, size, marker)) in rules.iter().enumerate() {
+        let sep = if i + 1 < rules.len() { "," } else { "" };
+        if *marker == 5 {
+            writeln!(
+                out,
+                "        {{\"min_name_length\": {}, \"block_size\": \"(name_length + 21) / 16 * 16\", \"marker\": 5, \"length\": \"block_size - 5\"}}{}",
+                min_len, sep
+            )?;
+        } else {
+            writeln!(
+                out,
+                "        {{\"min_name_length\": {}, \"max_name_length\": {}, \"block_size\": {}, \"marker\": {}}}{}",
+                min_len, max_len, size, marker, sep
+            )?;
+        }
+    }
+    writeln!(out, "      ]")?;
+    writeln!(out, "    }},")?;
+    writeln!(out, "    \"fields\": [")?;
+    write_fields(&mut out, ENTRY_FIELDS)?;
+    writeln!(out, "    ]")?;
+    writeln!(out, "  }},")?;
+    writeln!(out, "  \"content\": {{")?;
+    writeln!(out, "    \"offset\": \"{} + index_size\",", HEADER_SIZE)?;
+    writeln!(
+        out,
+        "    \"key_stream\": \"the low byte of every output of MT19937 seeded with (version << 7) ^ 0xA9C36DE1, XORed into the stored bytes\","
+    )?;
+    writeln!(out, "    \"compression_flags\": {{")?;
+    let compressions = [
+        Compression::Stored,
+        Compression::Zlib,
+        Compression::Gzip,
+        Compression::RawDeflate,
+    ];
+    for (i, c) in compressions.iter().enumerate() {
+        writeln!(
+            out,
+            "      \"{}\": \"{}\"{}",
+            c.flag(),
+            c,
+            if i + 1 < compressions.len() { "," } else { "" }
+        )?;
+    }
+    writeln!(out, "    }}")?;
+    writeln!(out, "  }},")?;
+    writeln!(out, "  \"trailer\": {{")?;
+    writeln!(out, "    \"optional\": true,")?;
+    writeln!(out, "    \"size\": {},", TRAILER_SIZE)?;
+    writeln!(
+        out,
+        "    \"layout\": \"magic MPTR, u32 version, then the sha256 of all the bytes before it\""
+    )?;
+    writeln!(out, "  }}")?;
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+/// Prints the first `len` bytes of the key stream of a version, in hex like
+/// `xxd -p` or raw with `raw`, to compare them with another implementation.
+pub fn run_dump_keystream(
+    version: &str,
+    len: u64,
+    key_generator: KeyGenerator,
+    raw: bool,
+) -> Result<(), MabiError> {
+    let version = parse_version(version).ok_or(MabiError::InvalidVersion)?;
+    // XORed into zeros, the key stream itself
+    let mut stream = vec![0; len as usize];
+    keystream::xor(&mut stream, version, key_generator);
+    let mut out = io::stdout().lock();
+    if raw {
+        out.write_all(&stream)?;
+        return Ok(());
+    }
+    for line in stream.chunks(30) {
+        for b in line {
+            write!(out, "{:02x}", b)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
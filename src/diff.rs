@@ -0,0 +1,92 @@
+use super::archive::Pack;
+use super::{normalize_name, FileInfo, MabiError, ReadOptions};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+#[derive(Default)]
+pub struct DiffOptions {
+    /// Decode the files present in both packs and compare their content,
+    /// instead of comparing only their version and size.
+    pub compare_content: bool,
+    pub read_options: ReadOptions,
+}
+
+// (name, version, uncompr_size) of the last entry of every name, which is the
+// one the game reads, sorted by name.
+fn latest_by_name(entries: &[FileInfo]) -> BTreeMap<String, (String, u32, u32)> {
+    entries
+        .iter()
+        .map(|e| {
+            (
+                normalize_name(&e.name),
+                (e.name.clone(), e.version, e.uncompr_size),
+            )
+        })
+        .collect()
+}
+
+// The offset of the first byte differing, or the end of the shorter one.
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    a.iter()
+        .zip(b)
+        .position(|(x, y)| x != y)
+        .or_else(|| (a.len() != b.len()).then(|| a.len().min(b.len())))
+}
+
+/// Compares the files of two packs by name, printing `- NAME` for the files
+/// only in the old pack, `+ NAME` for the files only in the new one, and
+/// `M NAME: ...` with what changed for the others. Files are modified when their
+/// version or size changed, or with `compare_content`, when their decoded
+/// content differs, with the offset of the first difference.
+pub fn run_diff(old_fname: &str, new_fname: &str, options: &DiffOptions) -> Result<(), MabiError> {
+    let mut old_pack = Pack::open_with(old_fname, &options.read_options)?;
+    let mut new_pack = Pack::open_with(new_fname, &options.read_options)?;
+    let old_files = latest_by_name(old_pack.entries());
+    let new_files = latest_by_name(new_pack.entries());
+
+    let mut output_stream = io::stdout().lock();
+    let (mut added, mut removed, mut modified) = (0, 0, 0);
+    for (key, (name, version, size)) in &old_files {
+        if !new_files.contains_key(key) {
+            writeln!(output_stream, "- {}", name)?;
+            removed += 1;
+            continue;
+        }
+        let (new_name, new_version, new_size) = &new_files[key];
+        let mut changes = vec![];
+        if version != new_version {
+            changes.push(format!("version {} -> {}", version, new_version));
+        }
+        if size != new_size {
+            changes.push(format!("size {} -> {}", size, new_size));
+        }
+        if options.compare_content {
+            let old_content = old_pack
+                .read(name)
+                .map_err(|e| MabiError::ExtractFail(name.clone(), e.to_string()))?;
+            let new_content = new_pack
+                .read(new_name)
+                .map_err(|e| MabiError::ExtractFail(new_name.clone(), e.to_string()))?;
+            match first_difference(&old_content, &new_content) {
+                Some(off) => changes.push(format!("content differs at 0x{:x}", off)),
+                // re-encrypted with another version, but identical
+                None => changes.clear(),
+            }
+        }
+        if !changes.is_empty() {
+            writeln!(output_stream, "M {}: {}", new_name, changes.join(", "))?;
+            modified += 1;
+        }
+    }
+    for (key, (name, _, _)) in &new_files {
+        if !old_files.contains_key(key) {
+            writeln!(output_stream, "+ {}", name)?;
+            added += 1;
+        }
+    }
+    info!(
+        "{} added, {} removed, {} modified",
+        added, removed, modified
+    );
+    Ok(())
+}
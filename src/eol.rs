@@ -0,0 +1,75 @@
+use super::{glob_to_regex, MabiError};
+use regex::Regex;
+use std::str::FromStr;
+
+/// A line ending text files are converted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    Crlf,
+}
+
+impl FromStr for LineEnding {
+    type Err = MabiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lf" => Ok(LineEnding::Lf),
+            "crlf" => Ok(LineEnding::Crlf),
+            _ => Err(MabiError::InvalidArgument(format!("line ending: {}", s))),
+        }
+    }
+}
+
+/// Converts the line endings of the files matching glob patterns, e.g. so that
+/// scripts packed on Windows and on Linux give the same pack. A pattern without
+/// a separator matches the file name in any folder, e.g. `*.xml`, and one with
+/// a separator the whole path, see `glob_to_regex`. Other files are left alone.
+#[derive(Debug, Clone)]
+pub struct TextEol {
+    eol: LineEnding,
+    // with whether it matches the whole path or only the file name
+    patterns: Vec<(Regex, bool)>,
+}
+
+impl TextEol {
+    pub fn new(eol: LineEnding, globs: &[&str]) -> Result<TextEol, MabiError> {
+        let patterns = globs
+            .iter()
+            .map(|glob| {
+                Regex::new(&glob_to_regex(glob))
+                    .map(|re| (re, glob.contains(['/', '\\'])))
+                    .map_err(|e| MabiError::InvalidRegexp(e.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(TextEol { eol, patterns })
+    }
+
+    /// Whether a file is converted, its name having either separator.
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.replace('/', "\\");
+        let file_name = name.rsplit('\\').next().unwrap_or_default();
+        self.patterns
+            .iter()
+            .any(|(re, whole_path)| re.is_match(if *whole_path { &name } else { file_name }))
+    }
+
+    /// Converts the line endings of a file if it matches.
+    pub fn apply(&self, name: &str, content: Vec<u8>) -> Vec<u8> {
+        if !self.matches(name) {
+            return content;
+        }
+        let mut converted = Vec::with_capacity(content.len());
+        let mut bytes = content.iter().peekable();
+        while let Some(&b) = bytes.next() {
+            if b == b'\r' && bytes.peek() == Some(&&b'\n') {
+                continue;
+            }
+            if b == b'\n' && self.eol == LineEnding::Crlf {
+                converted.push(b'\r');
+            }
+            converted.push(b);
+        }
+        converted
+    }
+}
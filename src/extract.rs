@@ -1,13 +1,319 @@
-use super::{read_header, read_index, FileInfo, HeadInfo, MabiError, HEADER_SIZE};
-use libflate::zlib;
-use mersenne_twister::MT19937;
-use rand::{Rng, SeedableRng};
+use super::codec::{decompress, has_zlib_header, make_decoder};
+use super::eol::TextEol;
+use super::json::Object;
+use super::keystream::{Keystream, KeystreamReader};
+use super::progress::Progress;
+use super::{
+    check_interrupted, latest_entries, normalize_name, open_pack, read_header_with,
+    read_index_with, Compression, FileInfo, HeadInfo, MabiError, ReadOptions,
+};
 use regex::Regex;
+use std::collections::{HashMap, HashSet};
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Cursor, Read, Seek, SeekFrom, Write};
-use std::path::{Path, MAIN_SEPARATOR};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR, MAIN_SEPARATOR_STR};
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-fn make_regex(strs: Vec<&str>) -> Result<Vec<Regex>, MabiError> {
+#[derive(Default)]
+pub struct ExtractOptions {
+    /// Stop after extracting this many (matching) files.
+    pub limit: Option<usize>,
+    /// How many times to retry writing a file after a transient io error.
+    pub retries: u32,
+    /// Write the files as `00001.bin`, `00002.bin`... numbered by their position
+    /// in the index, and their original names to `sequence.txt`.
+    pub sequence: bool,
+    /// Don't rewrite files which already exist with the same content, keeping
+    /// their modification time.
+    pub skip_unchanged: bool,
+    /// Leave the runs of zeros of at least `SPARSE_BLOCK_SIZE` aligned bytes
+    /// unwritten, so that they are holes on file systems supporting sparse
+    /// files. Elsewhere, and on NTFS where files must be marked sparse first,
+    /// they're written as zeros.
+    pub sparse: bool,
+    /// Write the names of the manifest of `run_extract_blob` with `/` instead
+    /// of the `\` of the pack. The files on disk always use the separator of
+    /// the system.
+    pub unix_paths: bool,
+    /// Also write the versions and names of the files in index order to
+    /// `order.txt`, to restore the same order with `pack --from-list`.
+    pub order_list: bool,
+    /// Recreate the empty folders recorded in the pack (entries whose name ends
+    /// with `\`), which are skipped otherwise.
+    pub restore_empty_dirs: bool,
+    pub read_options: ReadOptions,
+    /// Convert the line endings of the matching text files once decoded.
+    pub text_eol: Option<TextEol>,
+    /// Only extract the highest version of files present several times in the
+    /// pack, like the game does.
+    pub latest: bool,
+    /// Refuse to decode an entry larger than this many bytes, to safely extract
+    /// untrusted packs.
+    pub max_entry_size: Option<u64>,
+    /// Refuse to decode an entry expanding more than this many times its stored
+    /// size, which is a decompression bomb. zlib can't legitimately go past 1032.
+    pub max_ratio: Option<u64>,
+    /// Decode zlib entries without a zlib header as raw deflate, which some
+    /// variant packs use.
+    pub deflate_fallback: bool,
+    /// Don't check that the output volume has room for the files before
+    /// extracting them.
+    pub skip_space_check: bool,
+    /// Recreate entries sharing the content of an entry already extracted (see
+    /// `PackOptions::dedupe`) as relative symbolic links to it, or as copies
+    /// where links can't be created.
+    pub link_dupes: bool,
+    pub link_policy: LinkPolicy,
+    /// Extract the files not matching the filters instead.
+    pub invert_filter: bool,
+    /// Record the files written to this state file, and skip the files it
+    /// already lists, to resume an interrupted extraction. See `open_resume_state`.
+    pub resume: Option<String>,
+    /// Only extract the files with these exact names (see `normalize_name`)
+    /// instead of using filters.
+    pub names: Option<Vec<String>>,
+    /// Set to stop extracting, e.g. from a cancel button: `run_extract` then
+    /// fails with `Cancelled` before the next file. The files already written
+    /// are complete, and with `resume` recorded.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// What to do with files whose names differ only in case from a file
+    /// extracted before, which overwrite it on case-insensitive filesystems.
+    /// They are extracted as is when not set.
+    pub case_conflict: Option<CaseConflict>,
+    /// Fail with `LowFreeSpace` once the output volume has less than this many
+    /// bytes left, checked every `FREE_SPACE_CHECK_INTERVAL` files, e.g. when
+    /// other programs write to the same volume.
+    pub min_free_space: Option<u64>,
+    /// Report the progress on stderr as JSON lines, like
+    /// `PackOptions::progress_json`.
+    pub progress_json: bool,
+    /// Give the written files these Unix permissions (e.g. `0o644`) instead of
+    /// the default ones, as the format doesn't store any. Unix only.
+    pub file_mode: Option<u32>,
+    /// Give the created folders these Unix permissions. Unix only.
+    pub dir_mode: Option<u32>,
+    /// Write at most this many bytes per second on average, sleeping between
+    /// files, to leave IO to other programs.
+    pub max_throughput: Option<u64>,
+    /// What to do with files present several times in the pack, which
+    /// overwrite each other when not set, the last one in the index winning.
+    pub on_duplicate: Option<OnDuplicate>,
+    /// Warn about the filters matching no file of the pack, often a typo, once
+    /// done.
+    pub report_unmatched_filters: bool,
+    /// Then also fail with `NotFound`.
+    pub error_on_unmatched: bool,
+    /// Write the files in this order instead of the order of the index. The
+    /// content is still read from where the index says.
+    pub order_by: Option<ExtractOrder>,
+}
+
+/// How `link_dupes` recreates the files sharing the content of a file already
+/// extracted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LinkPolicy {
+    /// As relative symbolic links, or copies where they can't be created.
+    #[default]
+    Symlink,
+    /// As hard links, or copies where they can't be created.
+    Hardlink,
+    /// As copies, i.e. regular files.
+    Copy,
+}
+
+impl FromStr for LinkPolicy {
+    type Err = MabiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "symlink" => Ok(LinkPolicy::Symlink),
+            "hardlink" => Ok(LinkPolicy::Hardlink),
+            "copy" => Ok(LinkPolicy::Copy),
+            _ => Err(MabiError::InvalidArgument(format!("symlink policy: {}", s))),
+        }
+    }
+}
+
+/// How `case_conflict` handles a file whose name differs only in case from a
+/// file extracted before, e.g. `Foo.dds` and `foo.dds`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseConflict {
+    /// Print a warning and extract it as is.
+    Warn,
+    /// Extract it under a new name, with `~1`, `~2`... appended to its stem.
+    Rename,
+    /// Fail with `ExtractFail`.
+    Error,
+}
+
+impl FromStr for CaseConflict {
+    type Err = MabiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(CaseConflict::Warn),
+            "rename" => Ok(CaseConflict::Rename),
+            "error" => Ok(CaseConflict::Error),
+            _ => Err(MabiError::InvalidArgument(format!("case conflict: {}", s))),
+        }
+    }
+}
+
+/// An order for `order_by`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtractOrder {
+    /// Smallest first, writing the small files together.
+    Size,
+    /// By name, compared case-insensitively, writing the files of a folder
+    /// together.
+    Name,
+}
+
+impl FromStr for ExtractOrder {
+    type Err = MabiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "size" => Ok(ExtractOrder::Size),
+            "name" => Ok(ExtractOrder::Name),
+            _ => Err(MabiError::InvalidArgument(format!("order: {}", s))),
+        }
+    }
+}
+
+/// How `on_duplicate` handles files present several times in the pack under the
+/// same name, compared case-insensitively like the game does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnDuplicate {
+    /// Only extract the one with the highest version, like `latest`.
+    KeepLatest,
+    /// Extract them all, the later ones with `~1`, `~2`... appended to their
+    /// stem.
+    KeepAll,
+    /// Fail with `ExtractFail` before extracting anything.
+    Error,
+}
+
+impl FromStr for OnDuplicate {
+    type Err = MabiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-latest" => Ok(OnDuplicate::KeepLatest),
+            "keep-all" => Ok(OnDuplicate::KeepAll),
+            "error" => Ok(OnDuplicate::Error),
+            _ => Err(MabiError::InvalidArgument(format!("on duplicate: {}", s))),
+        }
+    }
+}
+
+// `rel_path` with `~n` appended to the stem of its file name.
+fn numbered_path(rel_path: &str, n: u32) -> String {
+    let (dir, file) = match rel_path.rfind('\\') {
+        Some(i) => rel_path.split_at(i + 1),
+        None => ("", rel_path),
+    };
+    match file.rfind('.') {
+        Some(i) if i > 0 => format!("{}{}~{}{}", dir, &file[..i], n, &file[i..]),
+        _ => format!("{}{}~{}", dir, file, n),
+    }
+}
+
+// The first of `rel_path` numbered with `~1`, `~2`... whose lowercased form
+// isn't taken.
+fn free_numbered_path(rel_path: &str, taken: impl Fn(&str) -> bool) -> String {
+    (1..)
+        .map(|n| numbered_path(rel_path, n))
+        .find(|p| !taken(&p.to_lowercase()))
+        .unwrap()
+}
+
+/// Returns the path to extract a file to with `OnDuplicate::KeepAll`: its name
+/// the first time, then a numbered one. `used` holds the lowercased paths
+/// given so far, and new names are never taken from `reserved` (see
+/// `resolve_case_conflict`).
+fn resolve_duplicate(
+    used: &mut HashSet<String>,
+    reserved: &HashSet<String>,
+    rel_path: String,
+) -> String {
+    if used.insert(rel_path.to_lowercase()) {
+        return rel_path;
+    }
+    let renamed = free_numbered_path(&rel_path, |p| used.contains(p) || reserved.contains(p));
+    verbose!("{}: duplicate, extracted as {}", rel_path, renamed);
+    used.insert(renamed.to_lowercase());
+    renamed
+}
+
+/// Handles a file extracted to `rel_path` colliding with a file extracted
+/// before once case is ignored, and returns the path to extract it to. `seen`
+/// maps the lowercased paths of the files extracted so far to their path, and
+/// new names are never taken from `reserved`, the lowercased names of all the
+/// files to extract.
+fn resolve_case_conflict(
+    seen: &mut HashMap<String, String>,
+    reserved: &HashSet<String>,
+    rel_path: String,
+    policy: CaseConflict,
+) -> Result<String, MabiError> {
+    let key = rel_path.to_lowercase();
+    let first = match seen.get(&key) {
+        // the same name twice (e.g. another version) isn't a case conflict
+        Some(first) if *first != rel_path => first.clone(),
+        _ => {
+            seen.insert(key, rel_path.clone());
+            return Ok(rel_path);
+        }
+    };
+    match policy {
+        CaseConflict::Warn => {
+            info!("warning: {} differs only in case from {}", rel_path, first);
+            Ok(rel_path)
+        }
+        CaseConflict::Error => Err(MabiError::ExtractFail(
+            rel_path,
+            format!("differs only in case from {}", first),
+        )),
+        CaseConflict::Rename => {
+            let renamed =
+                free_numbered_path(&rel_path, |p| seen.contains_key(p) || reserved.contains(p));
+            verbose!("{}: extracted as {}", rel_path, renamed);
+            seen.insert(renamed.to_lowercase(), renamed.clone());
+            Ok(renamed)
+        }
+    }
+}
+
+pub const SEQUENCE_FILE_NAME: &str = "sequence.txt";
+pub const ORDER_FILE_NAME: &str = "order.txt";
+/// The size of the blocks of zeros `sparse` leaves unwritten, that of a page
+/// and of most file system blocks.
+pub const SPARSE_BLOCK_SIZE: usize = 4096;
+
+/// Reads a list of names from a file, or from stdin for `-`, one per line or
+/// separated by NUL characters, e.g. from `list --print0`.
+pub fn read_name_list(path: &str, nul_separated: bool) -> Result<Vec<String>, MabiError> {
+    let mut text = String::new();
+    if path == "-" {
+        io::stdin().lock().read_to_string(&mut text)?;
+    } else {
+        File::open(path)?.read_to_string(&mut text)?;
+    }
+    let separator = if nul_separated { '\0' } else { '\n' };
+    Ok(text
+        .split(separator)
+        .map(|l| l.trim_end_matches('\r'))
+        .filter(|l| !l.is_empty())
+        .map(|l| l.to_string())
+        .collect())
+}
+
+pub(crate) fn make_regex(strs: Vec<&str>) -> Result<Vec<Regex>, MabiError> {
     strs.into_iter()
         .map(|s| {
             Regex::new(&s)
@@ -16,60 +322,813 @@ fn make_regex(strs: Vec<&str>) -> Result<Vec<Regex>, MabiError> {
         .collect()
 }
 
-fn write_file(root_dir: &str, rel_path: &str, content: Vec<u8>) -> Result<(), MabiError> {
+/// Whether a name passes the filters: matching any of them, or none of them
+/// when inverted. Every name passes when there's no filter.
+pub(crate) fn matches_filters(filters: &[Regex], invert: bool, name: &str) -> bool {
+    filters.is_empty() || filters.iter().any(|re| re.is_match(name)) != invert
+}
+
+// Errors worth retrying, e.g. on a flaky network share. Errors like a full disk
+// or a denied permission won't go away by retrying.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+// Compares chunk by chunk, only when the sizes already match.
+fn has_same_content(fname: &Path, content: &[u8]) -> io::Result<bool> {
+    let mut fs = File::open(fname)?;
+    if fs.metadata()?.len() != content.len() as u64 {
+        return Ok(false);
+    }
+    let mut buff = vec![0; 0x10000];
+    for chunk in content.chunks(buff.len()) {
+        fs.read_exact(&mut buff[..chunk.len()])?;
+        if &buff[..chunk.len()] != chunk {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn write_file(
+    root_dir: &str,
+    rel_path: &str,
+    content: Vec<u8>,
+    options: &ExtractOptions,
+) -> Result<(), MabiError> {
     let fname = Path::new(root_dir).join(rel_path.replace("\\", &MAIN_SEPARATOR.to_string()));
     let par = fname.parent().ok_or(MabiError::UnrecognizedPath(
         fname.to_string_lossy().into_owned(),
     ))?;
-    std::fs::create_dir_all(par)?;
-    let mut fs = OpenOptions::new().create(true).write(true).open(fname)?;
-    fs.write_all(&content)?;
+    create_dirs(par, options)?;
+    if options.skip_unchanged && fname.is_file() && has_same_content(&fname, &content)? {
+        return Ok(());
+    }
+    let mut attempt = 0;
+    loop {
+        let ret = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&fname)
+            .and_then(|mut fs| {
+                if options.sparse {
+                    write_sparse(&mut fs, &content)
+                } else {
+                    fs.write_all(&content)
+                }
+            });
+        match ret {
+            Err(e) if attempt < options.retries && is_transient(&e) => {
+                attempt += 1;
+                thread::sleep(Duration::from_millis(100 << attempt.min(6)));
+            }
+            ret => break ret?,
+        }
+    }
+    if let Some(mode) = options.file_mode {
+        set_mode(&fname, mode)?;
+    }
     Ok(())
 }
 
-fn extract_file(
-    stm: &mut BufReader<File>,
-    head_info: &HeadInfo,
-    file_info: &FileInfo,
+// Writes the content, seeking over its blocks of zeros instead of writing them.
+// Setting the length at the end keeps a hole at the end of the file.
+fn write_sparse(fs: &mut File, content: &[u8]) -> io::Result<()> {
+    let mut start = 0;
+    for (i, block) in content.chunks(SPARSE_BLOCK_SIZE).enumerate() {
+        let off = i * SPARSE_BLOCK_SIZE;
+        if block.len() == SPARSE_BLOCK_SIZE && block.iter().all(|&b| b == 0) {
+            fs.write_all(&content[start..off])?;
+            fs.seek(SeekFrom::Start((off + SPARSE_BLOCK_SIZE) as u64))?;
+            start = off + SPARSE_BLOCK_SIZE;
+        }
+    }
+    fs.write_all(&content[start..])?;
+    fs.set_len(content.len() as u64)
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// Like create_dir_all, giving the folders it creates `dir_mode`. The mode is
+// set afterwards, as the umask would mask the one given at creation.
+fn create_dirs(path: &Path, options: &ExtractOptions) -> io::Result<()> {
+    let Some(mode) = options.dir_mode else {
+        return std::fs::create_dir_all(path);
+    };
+    let missing: Vec<&Path> = path
+        .ancestors()
+        .take_while(|p| !p.as_os_str().is_empty() && !p.exists())
+        .collect();
+    std::fs::create_dir_all(path)?;
+    for dir in missing {
+        set_mode(dir, mode)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink(_target: &Path, _link: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// Both names are relative to root_dir, so the link goes up one folder per
+// separator in rel_path, then down to target.
+fn link_file(
     root_dir: &str,
+    target: &str,
+    rel_path: &str,
+    options: &ExtractOptions,
 ) -> Result<(), MabiError> {
+    let fname = Path::new(root_dir).join(rel_path.replace('\\', MAIN_SEPARATOR_STR));
+    let par = fname.parent().ok_or(MabiError::UnrecognizedPath(
+        fname.to_string_lossy().into_owned(),
+    ))?;
+    create_dirs(par, options)?;
+    match std::fs::remove_file(&fname) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let target = target.replace('\\', MAIN_SEPARATOR_STR);
+    let link_target: PathBuf = std::iter::repeat_n("..", rel_path.matches('\\').count())
+        .collect::<PathBuf>()
+        .join(&target);
+    let target = Path::new(root_dir).join(&target);
+    // e.g. windows without the privilege to create symbolic links, or a hard
+    // link across volumes
+    let linked = match options.link_policy {
+        LinkPolicy::Symlink => symlink(&link_target, &fname).is_ok(),
+        LinkPolicy::Hardlink => std::fs::hard_link(&target, &fname).is_ok(),
+        LinkPolicy::Copy => false,
+    };
+    if !linked {
+        std::fs::copy(&target, &fname)?;
+    }
+    Ok(())
+}
+
+/// The stored content of an entry, decrypted as it is read.
+fn decrypting_reader<'a>(
+    stm: &'a mut BufReader<File>,
+    head_info: &HeadInfo,
+    file_info: &FileInfo,
+    read_options: &ReadOptions,
+) -> io::Result<KeystreamReader<io::Take<&'a mut BufReader<File>>>> {
     stm.seek(SeekFrom::Start(
-        HEADER_SIZE + head_info.index_size as u64 + file_info.off as u64,
+        head_info.header_size + head_info.index_size as u64 + file_info.off as u64,
     ))?;
+    let seed = read_options
+        .seed_derivation
+        .seed(file_info.version, &file_info.name, file_info.off);
+    let keystream = Keystream::with_seed(seed, read_options.key_generator);
+    Ok(KeystreamReader::new(
+        stm.take(file_info.raw_size as u64),
+        keystream,
+    ))
+}
+
+fn read_decrypted(
+    stm: &mut BufReader<File>,
+    head_info: &HeadInfo,
+    file_info: &FileInfo,
+    read_options: &ReadOptions,
+) -> Result<Vec<u8>, MabiError> {
     let mut buff = vec![0; file_info.raw_size as usize];
-    stm.read_exact(&mut buff)?;
+    decrypting_reader(stm, head_info, file_info, read_options)?.read_exact(&mut buff)?;
+    Ok(buff)
+}
 
-    let mut rng: MT19937 = SeedableRng::from_seed((file_info.version << 7) ^ 0xA9C36DE1);
-    for i in 0..buff.len() {
-        buff[i] ^= rng.next_u32() as u8;
+/// Reads, decrypts and decompresses the content of an entry. Fails with
+/// `EntryTooLarge` instead of decoding more than `max_entry_size` bytes, or
+/// with `SuspectedBomb` instead of decoding more than `max_ratio` times the
+/// stored size, whatever the entry declares.
+pub(crate) fn read_file_content_with(
+    stm: &mut BufReader<File>,
+    head_info: &HeadInfo,
+    file_info: &FileInfo,
+    options: &ExtractOptions,
+) -> Result<Vec<u8>, MabiError> {
+    if file_info.raw_size == 0 && file_info.uncompr_size == 0 {
+        return Ok(vec![]);
     }
-
-    let mut decoder = zlib::Decoder::new(Cursor::new(buff)).unwrap();
-    let mut decoded_buff = vec![];
-    decoder.read_to_end(&mut decoded_buff)?;
+    let max_size = options.max_entry_size.unwrap_or(u64::MAX);
+    let max_ratio = options.max_ratio;
+    let max_ratio_size =
+        max_ratio.map_or(u64::MAX, |r| r.saturating_mul(file_info.raw_size as u64));
+    let check_size = |size: u64| {
+        if size > max_size {
+            Err(MabiError::EntryTooLarge(max_size))
+        } else if size > max_ratio_size {
+            Err(MabiError::SuspectedBomb(max_ratio.unwrap_or_default()))
+        } else {
+            Ok(())
+        }
+    };
+    check_size(file_info.uncompr_size as u64)?;
+    let buff = read_decrypted(stm, head_info, file_info, &options.read_options)?;
+    let mut compression = file_info.compression;
+    if compression == Compression::Zlib && options.deflate_fallback && !has_zlib_header(&buff) {
+        verbose!("{}: no zlib header, decoding as raw deflate", file_info.name);
+        compression = Compression::RawDeflate;
+    }
+    let decoded_buff = decompress(
+        buff,
+        compression,
+        max_size.min(max_ratio_size).saturating_add(1),
+    )?;
+    check_size(decoded_buff.len() as u64)?;
     if decoded_buff.len() != file_info.uncompr_size as usize {
         return Err(MabiError::CorruptedFile);
     }
-    write_file(root_dir, &file_info.name, decoded_buff)?;
+    Ok(decoded_buff)
+}
+
+/// Decodes only the first `len` bytes of an entry.
+pub(crate) fn read_file_head(
+    stm: &mut BufReader<File>,
+    head_info: &HeadInfo,
+    file_info: &FileInfo,
+    len: u64,
+    read_options: &ReadOptions,
+) -> Result<Vec<u8>, MabiError> {
+    read_file_range(stm, head_info, file_info, 0, len, read_options)
+}
+
+/// Decodes the entry up to `start + len` and returns the last `len` bytes, or
+/// less at the end of the file. The bytes before `start` are decoded but not
+/// kept, and the stored content is only read and decrypted as far as needed.
+pub(crate) fn read_file_range(
+    stm: &mut BufReader<File>,
+    head_info: &HeadInfo,
+    file_info: &FileInfo,
+    start: u64,
+    len: u64,
+    read_options: &ReadOptions,
+) -> Result<Vec<u8>, MabiError> {
+    if file_info.raw_size == 0 {
+        return Ok(vec![]);
+    }
+    let stored = decrypting_reader(stm, head_info, file_info, read_options)?;
+    let mut decoder = make_decoder(stored, file_info.compression)?;
+    io::copy(&mut decoder.by_ref().take(start), &mut io::sink())?;
+    let mut decoded_buff = vec![];
+    decoder.take(len).read_to_end(&mut decoded_buff)?;
+    Ok(decoded_buff)
+}
+
+// Identifies the pack a resume state was written for, as its indices are only
+// meaningful for that pack.
+fn resume_state_header(head_info: &HeadInfo) -> String {
+    format!(
+        "mabi-pack extract state: version {}, {} files, content 0x{:x}",
+        head_info.file_ver, head_info.file_cnt, head_info.content_size
+    )
+}
+
+/// Opens the state file of `extract --resume`: a header line identifying the
+/// pack, then the index of every file written, one per line, appended as soon
+/// as the file is complete. Returns the indices already written and the file
+/// to append to. A file being written when extraction stopped isn't listed
+/// yet, so it's written again.
+fn open_resume_state(
+    path: &str,
+    head_info: &HeadInfo,
+) -> Result<(HashSet<usize>, File), MabiError> {
+    let header = resume_state_header(head_info);
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines = text.lines();
+    let mut done = HashSet::new();
+    match lines.next() {
+        Some(line) if line == header => {
+            // the last line may be cut short by a crash, it's then ignored
+            done.extend(lines.filter_map(|l| l.parse::<usize>().ok()));
+        }
+        None => {}
+        Some(_) => {
+            return Err(MabiError::InvalidArgument(format!(
+                "{} is the resume state of another pack",
+                path
+            )))
+        }
+    }
+    let mut fs = OpenOptions::new().create(true).append(true).open(path)?;
+    if text.is_empty() {
+        writeln!(fs, "{}", header)?;
+    } else if !text.ends_with('\n') {
+        writeln!(fs)?;
+    }
+    Ok((done, fs))
+}
+
+fn record_done(state: &mut Option<File>, index: usize) -> io::Result<()> {
+    match state {
+        Some(fs) => fs.write_all(format!("{}\n", index).as_bytes()),
+        None => Ok(()),
+    }
+}
+
+fn extract_file(
+    stm: &mut BufReader<File>,
+    head_info: &HeadInfo,
+    file_info: &FileInfo,
+    root_dir: &str,
+    rel_path: &str,
+    options: &ExtractOptions,
+    transform: &mut impl FnMut(&str, Vec<u8>) -> Vec<u8>,
+) -> Result<u64, MabiError> {
+    let mut content = read_file_content_with(stm, head_info, file_info, options)?;
+    if let Some(text_eol) = &options.text_eol {
+        content = text_eol.apply(&file_info.name, content);
+    }
+    let content = transform(&file_info.name, content);
+    let size = content.len() as u64;
+    write_file(root_dir, rel_path, content, options)?;
+    Ok(size)
+}
+
+/// Limits the average rate of the writes to `max_throughput` bytes per second,
+/// sleeping once the bytes written get ahead of the time elapsed.
+struct Throttle {
+    max_throughput: Option<u64>,
+    start: Instant,
+    written: u64,
+}
+
+impl Throttle {
+    fn new(max_throughput: Option<u64>) -> Throttle {
+        Throttle {
+            max_throughput,
+            start: Instant::now(),
+            written: 0,
+        }
+    }
+
+    fn wrote(&mut self, bytes: u64) {
+        let Some(rate) = self.max_throughput.filter(|&r| r > 0) else {
+            return;
+        };
+        self.written += bytes;
+        let due = Duration::from_secs_f64(self.written as f64 / rate as f64);
+        if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+}
+
+/// How many files `extract` writes between two checks of `min_free_space`.
+pub const FREE_SPACE_CHECK_INTERVAL: usize = 64;
+
+// The output folder may not exist yet, so ask for the volume of its closest
+// existing parent.
+fn available_space(output_folder: &str) -> Result<u64, MabiError> {
+    let existing = Path::new(output_folder)
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or(Path::new("."));
+    Ok(fs2::available_space(existing)?)
+}
+
+fn check_space(output_folder: &str, needed: u64) -> Result<(), MabiError> {
+    let available = available_space(output_folder)?;
+    if needed > available {
+        return Err(MabiError::NotEnoughSpace(needed, available));
+    }
     Ok(())
 }
 
-pub fn run_extract(fname: &str, output_folder: &str, filters: Vec<&str>) -> Result<(), MabiError> {
-    let fs = File::open(fname)?;
+fn check_min_free_space(output_folder: &str, min: u64) -> Result<(), MabiError> {
+    let available = available_space(output_folder)?;
+    if available < min {
+        return Err(MabiError::LowFreeSpace(available, min));
+    }
+    Ok(())
+}
+
+/// Derives the output folder from the pack name, e.g. `339_full.pack` is
+/// extracted to `339_full` in the current folder. The folder must not exist,
+/// unless `reuse` as for `resume` or `skip_unchanged`, which continue a
+/// previous extraction into it.
+pub fn default_output_folder(pack_name: &str, reuse: bool) -> Result<String, MabiError> {
+    let folder = Path::new(pack_name)
+        .file_stem()
+        .map(Path::new)
+        .ok_or(MabiError::UnrecognizedPath(pack_name.to_string()))?;
+    if folder.exists() && !reuse {
+        return Err(MabiError::OutputExists(
+            folder.to_string_lossy().into_owned(),
+        ));
+    }
+    Ok(folder.to_string_lossy().into_owned())
+}
+
+// (off, raw_size, seed)
+type ContentId = (u32, u32, u32);
+
+/// Identifies what an entry decodes to: entries sharing a content region only
+/// decode to the same bytes if their key streams have the same seed too.
+fn content_id(fi: &FileInfo, read_options: &ReadOptions) -> ContentId {
+    let seed = read_options
+        .seed_derivation
+        .seed(fi.version, &fi.name, fi.off);
+    (fi.off, fi.raw_size, seed)
+}
+
+pub fn run_extract(
+    fname: &str,
+    output_folder: &str,
+    filters: Vec<&str>,
+    options: &ExtractOptions,
+) -> Result<(), MabiError> {
+    run_extract_with(fname, output_folder, filters, options, |_, content| content)
+}
+
+/// Like `run_extract`, passing the decoded content of every file with its name
+/// in the pack to `transform` before writing what it returns, e.g. to convert
+/// or inspect files. Files recreated as links by `link_dupes` aren't passed to
+/// it, they link to the transformed first file.
+pub fn run_extract_with(
+    fname: &str,
+    output_folder: &str,
+    filters: Vec<&str>,
+    options: &ExtractOptions,
+    mut transform: impl FnMut(&str, Vec<u8>) -> Vec<u8>,
+) -> Result<(), MabiError> {
+    // fail before any work rather than on the first file written
+    let output = Path::new(output_folder);
+    if output.exists() && !output.is_dir() {
+        return Err(MabiError::NotAFolder(output_folder.to_string()));
+    }
+    if !cfg!(unix) && (options.file_mode.is_some() || options.dir_mode.is_some()) {
+        return Err(MabiError::InvalidArgument(
+            "file modes are only supported on unix".to_string(),
+        ));
+    }
+    let fs = open_pack(fname)?;
     //let tra:Box<dyn Write> = Box::new(fs);
     let mut reader = BufReader::new(fs);
-    let head_info =
-        read_header(&mut reader).map_err(|e| MabiError::ReadHeaderFail(e.to_string()))?;
-    let file_entries =
-        read_index(&mut reader, &head_info).map_err(|e| MabiError::ReadIndexFail(e.to_string()))?;
+    let head_info = read_header_with(&mut reader, &options.read_options)
+        .map_err(|e| MabiError::ReadHeaderFail(e.to_string()))?;
+    let file_entries = read_index_with(&mut reader, &head_info, &options.read_options)?;
 
     let filters = make_regex(filters)?;
+    let names: Option<HashSet<String>> = options
+        .names
+        .as_ref()
+        .map(|names| names.iter().map(|n| normalize_name(n)).collect());
+    let unmatched_filters = unmatched_filters(&filters, &names, &file_entries, options);
+
+    let mut sequence_list = if options.sequence {
+        create_dirs(Path::new(output_folder), options)?;
+        let fs = File::create(Path::new(output_folder).join(SEQUENCE_FILE_NAME))?;
+        Some(BufWriter::new(fs))
+    } else {
+        None
+    };
+
+    let mut order_list = if options.order_list {
+        create_dirs(Path::new(output_folder), options)?;
+        let fs = File::create(Path::new(output_folder).join(ORDER_FILE_NAME))?;
+        Some(BufWriter::new(fs))
+    } else {
+        None
+    };
 
-    for fi in file_entries {
-        if filters.len() == 0 || filters.iter().any(|re| re.find(&fi.name).is_some()) {
-            extract_file(&mut reader, &head_info, &fi, output_folder)
-                .map_err(|e| MabiError::ExtractFail(fi.name, e.to_string()))?;
+    let mut selected = select_entries(file_entries, &filters, &names, options);
+    // before sorting, to keep the order of the index
+    if let Some(list) = order_list.as_mut() {
+        for (_, fi) in &selected {
+            writeln!(list, "{} {}", fi.version, fi.name)?;
         }
     }
+    sort_entries(&mut selected, options.order_by);
+    let (done, mut resume_state) = match &options.resume {
+        Some(path) => {
+            let (done, fs) = open_resume_state(path, &head_info)?;
+            (done, Some(fs))
+        }
+        None => (HashSet::new(), None),
+    };
+    if !options.skip_space_check {
+        check_space(
+            output_folder,
+            selected
+                .iter()
+                .filter(|(i, _)| !done.contains(i))
+                .map(|(_, fi)| fi.uncompr_size as u64)
+                .sum(),
+        )?;
+    }
+    let mut extracted: HashMap<ContentId, String> = HashMap::new();
+    let mut seen_paths: HashMap<String, String> = HashMap::new();
+    if options.on_duplicate == Some(OnDuplicate::Error) {
+        let mut names = HashSet::new();
+        for (_, fi) in selected.iter().filter(|(_, fi)| !fi.name.ends_with('\\')) {
+            if !names.insert(fi.name.to_lowercase()) {
+                return Err(MabiError::ExtractFail(
+                    fi.name.clone(),
+                    "present several times in the pack".to_string(),
+                ));
+            }
+        }
+    }
+    let keep_all = options.on_duplicate == Some(OnDuplicate::KeepAll);
+    let reserved_paths: HashSet<String> =
+        if keep_all || options.case_conflict == Some(CaseConflict::Rename) {
+            selected
+                .iter()
+                .map(|(_, fi)| fi.name.to_lowercase())
+                .collect()
+        } else {
+            HashSet::new()
+        };
+    let mut used_paths = HashSet::new();
+    let mut progress = Progress::new(options.progress_json, selected.len());
+    let mut throttle = Throttle::new(options.max_throughput);
+    for (n, (i, fi)) in selected.into_iter().enumerate() {
+        check_interrupted(&options.cancel)?;
+        progress.next(&fi.name);
+        progress.set_bytes(fi.uncompr_size as u64);
+        if let Some(min) = options.min_free_space {
+            if n % FREE_SPACE_CHECK_INTERVAL == 0 {
+                check_min_free_space(output_folder, min)?;
+            }
+        }
+        if fi.name.ends_with('\\') {
+            if options.restore_empty_dirs {
+                let dir = Path::new(output_folder).join(fi.name.replace('\\', MAIN_SEPARATOR_STR));
+                create_dirs(&dir, options)?;
+            }
+            continue;
+        }
+        let rel_path = match sequence_list.as_mut() {
+            Some(list) => {
+                let seq_name = format!("{:05}.bin", i + 1);
+                writeln!(list, "{} {}", seq_name, fi.name)?;
+                seq_name
+            }
+            None => {
+                let rel_path = if keep_all {
+                    resolve_duplicate(&mut used_paths, &reserved_paths, fi.name.clone())
+                } else {
+                    fi.name.clone()
+                };
+                match options.case_conflict {
+                    Some(policy) => {
+                        resolve_case_conflict(&mut seen_paths, &reserved_paths, rel_path, policy)?
+                    }
+                    None => rel_path,
+                }
+            }
+        };
+        if done.contains(&i) {
+            if options.link_dupes && fi.raw_size > 0 {
+                extracted
+                    .entry(content_id(&fi, &options.read_options))
+                    .or_insert(rel_path);
+            }
+            continue;
+        }
+        if options.link_dupes && fi.raw_size > 0 {
+            let id = content_id(&fi, &options.read_options);
+            if let Some(first) = extracted.get(&id) {
+                link_file(output_folder, first, &rel_path, options)
+                    .map_err(|e| MabiError::ExtractFail(fi.name, e.to_string()))?;
+                record_done(&mut resume_state, i)?;
+                continue;
+            }
+            extracted.insert(id, rel_path.clone());
+        }
+        let size = extract_file(
+            &mut reader,
+            &head_info,
+            &fi,
+            output_folder,
+            &rel_path,
+            options,
+            &mut transform,
+        )
+        .map_err(|e| MabiError::ExtractFail(fi.name, e.to_string()))?;
+        record_done(&mut resume_state, i)?;
+        throttle.wrote(size);
+    }
+    progress.finish();
+    for mut list in [sequence_list, order_list].into_iter().flatten() {
+        list.flush()?;
+    }
+    report_unmatched_filters(&unmatched_filters, options)
+}
+
+/// Decodes the files selected like `run_extract` into the single file
+/// `blob_fname`, one after the other, and writes to `manifest_fname` a JSON
+/// array of their names with the offset and length of their content in it,
+/// e.g. to load a whole pack with one read. Folders are skipped, and the
+/// options only about files on disk like `sequence` or `link_dupes` are
+/// ignored.
+pub fn run_extract_blob(
+    fname: &str,
+    blob_fname: &str,
+    manifest_fname: &str,
+    filters: Vec<&str>,
+    options: &ExtractOptions,
+) -> Result<(), MabiError> {
+    let fs = open_pack(fname)?;
+    let mut reader = BufReader::new(fs);
+    let head_info = read_header_with(&mut reader, &options.read_options)
+        .map_err(|e| MabiError::ReadHeaderFail(e.to_string()))?;
+    let file_entries = read_index_with(&mut reader, &head_info, &options.read_options)?;
+
+    let filters = make_regex(filters)?;
+    let names: Option<HashSet<String>> = options
+        .names
+        .as_ref()
+        .map(|names| names.iter().map(|n| normalize_name(n)).collect());
+    let unmatched_filters = unmatched_filters(&filters, &names, &file_entries, options);
+    let mut selected = select_entries(file_entries, &filters, &names, options);
+    selected.retain(|(_, fi)| !fi.name.ends_with('\\'));
+    sort_entries(&mut selected, options.order_by);
+    if !options.skip_space_check {
+        check_space(
+            blob_fname,
+            selected.iter().map(|(_, fi)| fi.uncompr_size as u64).sum(),
+        )?;
+    }
+
+    let mut blob = BufWriter::new(File::create(blob_fname)?);
+    let mut manifest = BufWriter::new(File::create(manifest_fname)?);
+    writeln!(manifest, "[")?;
+    let mut offset = 0u64;
+    let mut progress = Progress::new(options.progress_json, selected.len());
+    let count = selected.len();
+    for (n, (_, fi)) in selected.into_iter().enumerate() {
+        check_interrupted(&options.cancel)?;
+        progress.next(&fi.name);
+        progress.set_bytes(fi.uncompr_size as u64);
+        let mut content = read_file_content_with(&mut reader, &head_info, &fi, options)
+            .map_err(|e| MabiError::ExtractFail(fi.name.clone(), e.to_string()))?;
+        if let Some(text_eol) = &options.text_eol {
+            content = text_eol.apply(&fi.name, content);
+        }
+        blob.write_all(&content)?;
+        let name = if options.unix_paths {
+            fi.name.replace('\\', "/")
+        } else {
+            fi.name.clone()
+        };
+        let entry = Object::new()
+            .string("name", &name)
+            .value("offset", offset)
+            .value("length", content.len());
+        writeln!(
+            manifest,
+            "  {}{}",
+            entry,
+            if n + 1 == count { "" } else { "," }
+        )?;
+        offset += content.len() as u64;
+    }
+    writeln!(manifest, "]")?;
+    progress.finish();
+    blob.flush()?;
+    manifest.flush()?;
+    report_unmatched_filters(&unmatched_filters, options)
+}
+
+// The filters no file of the pack matches, when they are to be reported.
+fn unmatched_filters<'a>(
+    filters: &'a [Regex],
+    names: &Option<HashSet<String>>,
+    file_entries: &[FileInfo],
+    options: &ExtractOptions,
+) -> Vec<&'a str> {
+    if names.is_none() && (options.report_unmatched_filters || options.error_on_unmatched) {
+        filters
+            .iter()
+            .filter(|re| !file_entries.iter().any(|fi| re.is_match(&fi.name)))
+            .map(|re| re.as_str())
+            .collect()
+    } else {
+        vec![]
+    }
+}
+
+fn report_unmatched_filters(
+    unmatched_filters: &[&str],
+    options: &ExtractOptions,
+) -> Result<(), MabiError> {
+    for filter in unmatched_filters {
+        info!("warning: no file matches the filter {}", filter);
+    }
+    if options.error_on_unmatched && !unmatched_filters.is_empty() {
+        return Err(MabiError::NotFound(unmatched_filters.join(", ")));
+    }
     Ok(())
 }
+
+// The entries to extract with their index in the pack: the named ones or the
+// ones matching the filters, only the latest version of each if asked, up to
+// `limit` of them.
+fn select_entries(
+    file_entries: Vec<FileInfo>,
+    filters: &[Regex],
+    names: &Option<HashSet<String>>,
+    options: &ExtractOptions,
+) -> Vec<(usize, FileInfo)> {
+    let mut selected: Vec<_> = file_entries
+        .into_iter()
+        .enumerate()
+        .filter(|(_, fi)| match names {
+            Some(names) => names.contains(&normalize_name(&fi.name)),
+            None => matches_filters(filters, options.invert_filter, &fi.name),
+        })
+        .collect();
+    if options.latest || options.on_duplicate == Some(OnDuplicate::KeepLatest) {
+        selected = latest_entries(selected, |(_, fi)| (&fi.name, fi.version));
+    }
+    selected.truncate(options.limit.unwrap_or(usize::MAX));
+    selected
+}
+
+fn sort_entries(selected: &mut [(usize, FileInfo)], order_by: Option<ExtractOrder>) {
+    match order_by {
+        Some(ExtractOrder::Size) => selected.sort_by_key(|(_, fi)| fi.uncompr_size),
+        Some(ExtractOrder::Name) => selected.sort_by_cached_key(|(_, fi)| normalize_name(&fi.name)),
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::{run_pack, PackOptions};
+    use crate::temp_folder;
+
+    #[test]
+    fn link_dupes_needs_the_same_seed() {
+        let root = temp_folder("link-dupes");
+        let input = root.join("in");
+        std::fs::create_dir(&input).unwrap();
+        for name in ["a.bin", "b.bin"] {
+            std::fs::write(input.join(name), b"the same content").unwrap();
+        }
+        let pack = root.join("dupes.pack");
+        let pack_options = PackOptions {
+            dedupe: true,
+            compression: Compression::Stored,
+            ..Default::default()
+        };
+        let pack_name = pack.to_str().unwrap();
+        run_pack(input.to_str().unwrap(), pack_name, "1", &pack_options).unwrap();
+
+        // give b.bin another version: same region, another key stream
+        let mut bytes = std::fs::read(&pack).unwrap();
+        let block = bytes.windows(7).position(|w| w == b"\0b.bin\0").unwrap();
+        bytes[block + 16..block + 20].copy_from_slice(&2u32.to_le_bytes());
+        std::fs::write(&pack, &bytes).unwrap();
+
+        let output = root.join("out");
+        let options = ExtractOptions {
+            link_dupes: true,
+            ..Default::default()
+        };
+        run_extract(pack_name, output.to_str().unwrap(), vec![], &options).unwrap();
+        let a = std::fs::read(output.join("a.bin")).unwrap();
+        let b = std::fs::read(output.join("b.bin")).unwrap();
+        assert_eq!(a, b"the same content");
+        assert_ne!(a, b);
+        assert!(!std::fs::symlink_metadata(output.join("b.bin"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,28 @@
+// FILETIME counts 100ns intervals since 1601-01-01 UTC
+
+/// The UTC date and time of a FILETIME: year, month, day, hours, minutes and
+/// seconds.
+pub(crate) fn to_civil(t: u64) -> (i64, i64, i64, i64, i64, i64) {
+    let secs = (t / 10_000_000) as i64 - 11_644_473_600;
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Formats a FILETIME as ISO 8601 in UTC, e.g. `2024-05-01T12:30:00Z`.
+pub(crate) fn to_iso8601(t: u64) -> String {
+    let (year, month, day, hours, minutes, seconds) = to_civil(t);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hours, minutes, seconds
+    )
+}
@@ -0,0 +1,60 @@
+use super::filetime::to_civil;
+use super::{open_pack, read_header, read_index, MabiError};
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+
+fn format_filetime(t: u64) -> String {
+    let (year, month, day, hours, minutes, seconds) = to_civil(t);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year, month, day, hours, minutes, seconds
+    )
+}
+
+/// Prints a summary of a pack: its header, its size and how well it's
+/// compressed, without listing its files.
+pub fn run_info(fname: &str) -> Result<(), MabiError> {
+    let fs = open_pack(fname)?;
+    let file_size = fs.metadata()?.len();
+    let mut reader = BufReader::new(fs);
+    let head_info = read_header(&mut reader)?;
+    let file_entries = read_index(&mut reader, &head_info)?;
+
+    // read_header skips the timestamps and the root name
+    reader.seek(SeekFrom::Start(0x10))?;
+    let created = reader.read_u64::<LittleEndian>()?;
+    let modified = reader.read_u64::<LittleEndian>()?;
+    let mut root_name = vec![0; 0x1e0];
+    reader.read_exact(&mut root_name)?;
+    let root_name_len = root_name
+        .iter()
+        .position(|&c| c == 0)
+        .unwrap_or(root_name.len());
+    let root_name = String::from_utf8_lossy(&root_name[..root_name_len]);
+
+    let uncompr_size: u64 = file_entries.iter().map(|e| e.uncompr_size as u64).sum();
+    let min_version = file_entries.iter().map(|e| e.version).min();
+    let max_version = file_entries.iter().map(|e| e.version).max();
+
+    let mut out = io::stdout().lock();
+    writeln!(out, "pack version: {}", head_info.file_ver)?;
+    writeln!(out, "root name: {}", root_name)?;
+    writeln!(out, "created: {}", format_filetime(created))?;
+    writeln!(out, "modified: {}", format_filetime(modified))?;
+    writeln!(out, "files: {}", head_info.file_cnt)?;
+    writeln!(out, "index size: 0x{:x}", head_info.index_size)?;
+    writeln!(out, "content size: 0x{:x}", head_info.content_size)?;
+    writeln!(out, "file size: 0x{:x}", file_size)?;
+    writeln!(out, "uncompressed size: 0x{:x}", uncompr_size)?;
+    if uncompr_size > 0 {
+        writeln!(
+            out,
+            "compression ratio: {:.1}%",
+            head_info.content_size as f64 * 100.0 / uncompr_size as f64
+        )?;
+    }
+    if let (Some(min), Some(max)) = (min_version, max_version) {
+        writeln!(out, "file versions: {} - {}", min, max)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use std::fmt;
+
+/// Quotes and escapes a string as a JSON string literal.
+pub(crate) fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A JSON object written on one line, `{"key": value, ...}`, the form of the
+/// objects of every JSON output but error reports.
+#[derive(Default)]
+pub(crate) struct Object {
+    fields: Vec<String>,
+}
+
+impl Object {
+    pub(crate) fn new() -> Object {
+        Object::default()
+    }
+
+    /// Adds a string field.
+    pub(crate) fn string(self, key: &str, value: &str) -> Object {
+        self.value(key, quote(value))
+    }
+
+    /// Adds a field whose value is already JSON, e.g. a number or `null_or`.
+    pub(crate) fn value(mut self, key: &str, value: impl fmt::Display) -> Object {
+        self.fields.push(format!("{}: {}", quote(key), value));
+        self
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{{}}}", self.fields.join(", "))
+    }
+}
+
+/// A JSON array on one line of values already JSON.
+pub(crate) fn array<T: fmt::Display>(values: impl IntoIterator<Item = T>) -> String {
+    let values: Vec<String> = values.into_iter().map(|v| v.to_string()).collect();
+    format!("[{}]", values.join(", "))
+}
+
+/// A value already JSON, or `null`.
+pub(crate) fn null_or<T: fmt::Display>(value: Option<T>) -> String {
+    value.map_or("null".to_string(), |v| v.to_string())
+}
@@ -0,0 +1,290 @@
+use super::KeyGenerator;
+use std::io::{self, Read};
+
+/// A generator of key bytes, producing a whole block at a time.
+trait Generator {
+    /// Replaces `block` with the next bytes of the key stream.
+    fn next_block(&mut self, block: &mut Vec<u8>);
+}
+
+// The seed of every generator, from the version of the entry.
+pub(crate) fn seed(version: u32) -> u32 {
+    (version << 7) ^ 0xA9C36DE1
+}
+
+/// MT19937, the generator of the game. The outputs are generated a whole state
+/// (624 values) at a time, which gives exactly the bytes of calling
+/// `next_u32() as u8` once per byte.
+struct Mt19937 {
+    state: [u32; 624],
+}
+
+impl Mt19937 {
+    const N: usize = 624;
+    const M: usize = 397;
+
+    fn new(seed: u32) -> Mt19937 {
+        let mut state = [0u32; Self::N];
+        state[0] = seed;
+        for i in 1..Self::N {
+            state[i] = 1812433253u32
+                .wrapping_mul(state[i - 1] ^ (state[i - 1] >> 30))
+                .wrapping_add(i as u32);
+        }
+        Mt19937 { state }
+    }
+}
+
+impl Generator for Mt19937 {
+    // Twists the whole state, and keeps the low byte of its tempered values.
+    fn next_block(&mut self, block: &mut Vec<u8>) {
+        let (n, m) = (Self::N, Self::M);
+        for i in 0..n {
+            let y = (self.state[i] & 0x8000_0000) | (self.state[(i + 1) % n] & 0x7fff_ffff);
+            let mut v = self.state[(i + m) % n] ^ (y >> 1);
+            if y & 1 != 0 {
+                v ^= 0x9908_b0df;
+            }
+            self.state[i] = v;
+        }
+        block.clear();
+        block.extend(self.state.iter().map(|&s| {
+            let mut y = s;
+            y ^= y >> 11;
+            y ^= (y << 7) & 0x9d2c_5680;
+            y ^= (y << 15) & 0xefc6_0000;
+            y ^= y >> 18;
+            y as u8
+        }));
+    }
+}
+
+/// MT19937-64, seeded with the same value, keeping the low byte of every 64-bit
+/// output.
+struct Mt19937x64 {
+    state: [u64; 312],
+}
+
+impl Mt19937x64 {
+    const N: usize = 312;
+    const M: usize = 156;
+
+    fn new(seed: u64) -> Mt19937x64 {
+        let mut state = [0u64; Self::N];
+        state[0] = seed;
+        for i in 1..Self::N {
+            state[i] = 6364136223846793005u64
+                .wrapping_mul(state[i - 1] ^ (state[i - 1] >> 62))
+                .wrapping_add(i as u64);
+        }
+        Mt19937x64 { state }
+    }
+}
+
+impl Generator for Mt19937x64 {
+    fn next_block(&mut self, block: &mut Vec<u8>) {
+        let (n, m) = (Self::N, Self::M);
+        for i in 0..n {
+            let y =
+                (self.state[i] & 0xffff_ffff_8000_0000) | (self.state[(i + 1) % n] & 0x7fff_ffff);
+            let mut v = self.state[(i + m) % n] ^ (y >> 1);
+            if y & 1 != 0 {
+                v ^= 0xb502_6f5a_a966_19e9;
+            }
+            self.state[i] = v;
+        }
+        block.clear();
+        block.extend(self.state.iter().map(|&s| {
+            let mut y = s;
+            y ^= (y >> 29) & 0x5555_5555_5555_5555;
+            y ^= (y << 17) & 0x71d6_7fff_eda6_0000;
+            y ^= (y << 37) & 0xfff7_eee0_0000_0000;
+            y ^= y >> 43;
+            y as u8
+        }));
+    }
+}
+
+/// The key stream encrypting the content of an entry: the low byte of every
+/// output of a generator seeded with `(version << 7) ^ 0xA9C36DE1`. The
+/// outputs are XORed into the content a block at a time.
+pub(crate) struct Keystream {
+    generator: Box<dyn Generator>,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl Keystream {
+    pub(crate) fn new(version: u32, kind: KeyGenerator) -> Keystream {
+        Keystream::with_seed(seed(version), kind)
+    }
+
+    /// The key stream of a generator seeded with `seed` instead of the one of
+    /// a version.
+    pub(crate) fn with_seed(seed: u32, kind: KeyGenerator) -> Keystream {
+        let generator: Box<dyn Generator> = match kind {
+            KeyGenerator::Mt19937 => Box::new(Mt19937::new(seed)),
+            KeyGenerator::Mt19937x64 => Box::new(Mt19937x64::new(seed as u64)),
+        };
+        Keystream {
+            generator,
+            block: vec![],
+            pos: 0,
+        }
+    }
+
+    /// XORs the next `buff.len()` bytes of the key stream into `buff`.
+    pub(crate) fn apply(&mut self, mut buff: &mut [u8]) {
+        while !buff.is_empty() {
+            if self.pos == self.block.len() {
+                self.generator.next_block(&mut self.block);
+                self.pos = 0;
+            }
+            let n = buff.len().min(self.block.len() - self.pos);
+            let (chunk, rest) = std::mem::take(&mut buff).split_at_mut(n);
+            for (b, k) in chunk.iter_mut().zip(&self.block[self.pos..]) {
+                *b ^= k;
+            }
+            self.pos += n;
+            buff = rest;
+        }
+    }
+}
+
+/// Decrypts what is read from a stream, so that only the bytes actually read
+/// are decrypted.
+pub(crate) struct KeystreamReader<R> {
+    inner: R,
+    keystream: Keystream,
+}
+
+impl<R: Read> KeystreamReader<R> {
+    pub(crate) fn new(inner: R, keystream: Keystream) -> KeystreamReader<R> {
+        KeystreamReader { inner, keystream }
+    }
+}
+
+impl<R: Read> Read for KeystreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.keystream.apply(&mut buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Encrypts or decrypts the content of an entry of this version.
+pub(crate) fn xor(buff: &mut [u8], version: u32, kind: KeyGenerator) {
+    Keystream::new(version, kind).apply(buff);
+}
+
+/// Like `xor`, with the key stream of a generator seeded with `seed`.
+pub(crate) fn xor_seeded(buff: &mut [u8], seed: u32, kind: KeyGenerator) {
+    Keystream::with_seed(seed, kind).apply(buff);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MT19937 as usually written: one tempered output per call, twisting the
+    // whole state every 624 calls.
+    struct NaiveMt19937 {
+        state: [u32; 624],
+        index: usize,
+    }
+
+    impl NaiveMt19937 {
+        fn new(seed: u32) -> NaiveMt19937 {
+            let mut state = [0u32; 624];
+            state[0] = seed;
+            for i in 1..624 {
+                state[i] = 1812433253u32
+                    .wrapping_mul(state[i - 1] ^ (state[i - 1] >> 30))
+                    .wrapping_add(i as u32);
+            }
+            NaiveMt19937 { state, index: 624 }
+        }
+
+        fn next_u32(&mut self) -> u32 {
+            if self.index == 624 {
+                for i in 0..624 {
+                    let y =
+                        (self.state[i] & 0x8000_0000) | (self.state[(i + 1) % 624] & 0x7fff_ffff);
+                    let mut v = self.state[(i + 397) % 624] ^ (y >> 1);
+                    if y & 1 != 0 {
+                        v ^= 0x9908_b0df;
+                    }
+                    self.state[i] = v;
+                }
+                self.index = 0;
+            }
+            let mut y = self.state[self.index];
+            self.index += 1;
+            y ^= y >> 11;
+            y ^= (y << 7) & 0x9d2c_5680;
+            y ^= (y << 15) & 0xefc6_0000;
+            y ^= y >> 18;
+            y
+        }
+    }
+
+    #[test]
+    fn naive_reference_is_mt19937() {
+        // the first output of the reference implementation for its default seed
+        assert_eq!(NaiveMt19937::new(5489).next_u32(), 3499211612);
+    }
+
+    #[test]
+    fn default_seed_outputs() {
+        let mut mt = NaiveMt19937::new(seed(0));
+        assert_eq!(seed(0), 0xA9C36DE1);
+        let outputs: Vec<u32> = (0..4).map(|_| mt.next_u32()).collect();
+        assert_eq!(outputs, [0x13e8f38e, 0xe24b9242, 0x0f83060b, 0x08987b5f]);
+
+        // bytes 0..8, and 624..628 after the first refill
+        for (version, head, refill) in [
+            (
+                0,
+                [0x8e, 0x42, 0x0b, 0x5f, 0x0b, 0x75, 0x8e, 0x04],
+                [0x39, 0xed, 0xcc, 0xef],
+            ),
+            (
+                400,
+                [0x16, 0x2e, 0x04, 0xc0, 0x6e, 0xe5, 0xd5, 0xa8],
+                [0x94, 0x6d, 0x1e, 0x41],
+            ),
+        ] {
+            let mut buff = vec![0; 628];
+            xor(&mut buff, version, KeyGenerator::Mt19937);
+            assert_eq!(buff[..8], head);
+            assert_eq!(buff[624..], refill);
+        }
+    }
+
+    #[test]
+    fn keystream_matches_naive() {
+        let len = (3 << 20) + 17;
+        let original: Vec<u8> = (0..len).map(|i| (i * 31) as u8).collect();
+        let mut expected = original.clone();
+        let mut mt = NaiveMt19937::new(seed(400));
+        for b in expected.iter_mut() {
+            *b ^= mt.next_u32() as u8;
+        }
+
+        let mut buff = original.clone();
+        xor(&mut buff, 400, KeyGenerator::Mt19937);
+        assert!(buff == expected);
+
+        // applied in pieces not aligned on the blocks
+        let mut buff = original;
+        let mut keystream = Keystream::new(400, KeyGenerator::Mt19937);
+        let mut rest = &mut buff[..];
+        for n in [1, 623, 1, 1000, 624 * 3, 5] {
+            let (chunk, tail) = rest.split_at_mut(n);
+            keystream.apply(chunk);
+            rest = tail;
+        }
+        keystream.apply(rest);
+        assert!(buff == expected);
+    }
+}
@@ -0,0 +1,842 @@
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use thiserror::Error as ThisError;
+
+#[macro_use]
+pub mod log;
+
+pub mod archive;
+pub mod codec;
+pub mod describe;
+pub mod diff;
+pub mod eol;
+pub mod extract;
+mod filetime;
+pub mod info;
+mod keystream;
+mod json;
+pub mod list;
+#[cfg(feature = "tokio")]
+pub mod nonblocking;
+pub mod pack;
+mod progress;
+pub mod repair;
+mod report;
+pub mod trailer;
+pub mod verify;
+pub mod which;
+
+pub const HEADER_SIZE: u64 = 0x220;
+/// The end of the fields of the header, after which a header only holds
+/// padding, so the smallest `ReadOptions::header_size`.
+pub const MIN_HEADER_SIZE: u64 = 0x210;
+/// The size of an index entry with the shortest string block.
+const MIN_ENTRY_SIZE: u64 = 0x50;
+/// The default of `ReadOptions::max_entries`.
+pub const DEFAULT_MAX_ENTRIES: u32 = 10_000_000;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Asks the running pack or extraction to stop, a pack removing its incomplete
+/// output, e.g. from a Ctrl-C handler.
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Checked before every file: fails with `Cancelled` once the cancellation
+/// token of the options is set, or `Interrupted` after `interrupt`.
+pub(crate) fn check_interrupted(cancel: &Option<Arc<AtomicBool>>) -> Result<(), MabiError> {
+    if cancel.as_ref().is_some_and(|c| c.load(Ordering::SeqCst)) {
+        return Err(MabiError::Cancelled);
+    }
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        return Err(MabiError::Interrupted);
+    }
+    Ok(())
+}
+
+#[derive(ThisError, Debug)]
+pub enum MabiError {
+    #[error("io error: {0}")]
+    IoFail(#[from] std::io::Error),
+
+    #[error("format error")]
+    WrongFormat,
+
+    #[error("encoding error")]
+    Encoding(#[from] std::string::FromUtf8Error),
+
+    #[error("name not valid in {0}: {1}")]
+    NameEncoding(NameEncoding, String),
+
+    #[error("name the game can't load: {0}, {1}")]
+    InvalidName(String, String),
+
+    #[error("corrupted file")]
+    CorruptedFile,
+
+    #[error("entry larger than the limit of {0} bytes")]
+    EntryTooLarge(u64),
+
+    #[error("pack too large: {0} bytes, offsets and sizes are 32-bit in the format")]
+    PackTooLarge(u64),
+
+    #[error("suspected decompression bomb: entry expands more than {0} times")]
+    SuspectedBomb(u64),
+
+    #[error("unknown file path: {0}")]
+    UnrecognizedPath(String),
+
+    #[error("regular expression invalid: {0}")]
+    InvalidRegexp(String),
+
+    #[error("invalid version")]
+    InvalidVersion,
+
+    #[error("compress error: {0}")]
+    CompressError(String),
+
+    #[error("file time error")]
+    TimeError,
+
+    #[error("reading header fail: {0}")]
+    ReadHeaderFail(String),
+
+    #[error("reading index fail: {0}")]
+    ReadIndexFail(String),
+
+    #[error("error when extracting file:{0}, {1}")]
+    ExtractFail(String, String),
+
+    #[error("error in traversing the folder: {0}")]
+    TraversingFail(String),
+
+    #[error("output already exists: {0}")]
+    OutputExists(String),
+
+    #[error("not enough space: {0} bytes needed, {1} available")]
+    NotEnoughSpace(u64, u64),
+
+    #[error("free space fell below the minimum: {0} bytes available, {1} required")]
+    LowFreeSpace(u64, u64),
+
+    #[error("error when listing pack:{0}, {1}")]
+    ListFail(String, String),
+
+    #[error("no file matches: {0}")]
+    NotFound(String),
+
+    #[error("not a folder: {0}")]
+    NotAFolder(String),
+
+    #[error("output is inside the input folder: {0}")]
+    OutputInsideInput(String),
+
+    #[error("error in processing:{0}, {1}")]
+    PackingFail(String, String),
+
+    #[error("verification failed: {0} problem(s) found")]
+    VerifyFail(usize),
+
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+
+    #[error("interrupted")]
+    Interrupted,
+
+    #[error("cancelled")]
+    Cancelled,
+
+    #[error("Internal error")]
+    InternalError,
+}
+
+impl MabiError {
+    /// The name of the variant, e.g. `WrongFormat`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            MabiError::IoFail(_) => "IoFail",
+            MabiError::WrongFormat => "WrongFormat",
+            MabiError::Encoding(_) => "Encoding",
+            MabiError::NameEncoding(..) => "NameEncoding",
+            MabiError::InvalidName(..) => "InvalidName",
+            MabiError::CorruptedFile => "CorruptedFile",
+            MabiError::EntryTooLarge(_) => "EntryTooLarge",
+            MabiError::PackTooLarge(_) => "PackTooLarge",
+            MabiError::SuspectedBomb(_) => "SuspectedBomb",
+            MabiError::UnrecognizedPath(_) => "UnrecognizedPath",
+            MabiError::InvalidRegexp(_) => "InvalidRegexp",
+            MabiError::InvalidVersion => "InvalidVersion",
+            MabiError::CompressError(_) => "CompressError",
+            MabiError::TimeError => "TimeError",
+            MabiError::ReadHeaderFail(_) => "ReadHeaderFail",
+            MabiError::ReadIndexFail(_) => "ReadIndexFail",
+            MabiError::ExtractFail(..) => "ExtractFail",
+            MabiError::TraversingFail(_) => "TraversingFail",
+            MabiError::OutputExists(_) => "OutputExists",
+            MabiError::NotEnoughSpace(..) => "NotEnoughSpace",
+            MabiError::LowFreeSpace(..) => "LowFreeSpace",
+            MabiError::ListFail(..) => "ListFail",
+            MabiError::NotFound(_) => "NotFound",
+            MabiError::NotAFolder(_) => "NotAFolder",
+            MabiError::OutputInsideInput(_) => "OutputInsideInput",
+            MabiError::PackingFail(..) => "PackingFail",
+            MabiError::VerifyFail(_) => "VerifyFail",
+            MabiError::InvalidArgument(_) => "InvalidArgument",
+            MabiError::Interrupted => "Interrupted",
+            MabiError::Cancelled => "Cancelled",
+            MabiError::InternalError => "InternalError",
+        }
+    }
+
+    /// Describes the error as a JSON object, for programs wrapping this tool.
+    pub fn to_json(&self, file: Option<&str>) -> String {
+        format!(
+            "{{\"error\":{},\"message\":{},\"file\":{}}}",
+            json::quote(self.kind()),
+            json::quote(&self.to_string()),
+            file.map_or("null".to_string(), json::quote)
+        )
+    }
+}
+
+/// The header of a pack. With the `serde` feature, it serializes with the field
+/// names below, which are kept stable.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HeadInfo {
+    /// The version of the pack, e.g. 339 for `339_full.pack`, which `pack`
+    /// sets to its `-k` version. Unlike the version of an entry, it isn't used
+    /// to decrypt anything.
+    file_ver: u32,
+    /// The number of entries in the index.
+    file_cnt: u32,
+    /// The size of the index, which follows the header.
+    index_size: u32,
+    /// The size of the content, which follows the index.
+    content_size: u32,
+    /// The size of the header, where the index starts: `HEADER_SIZE` unless
+    /// read with another `ReadOptions::header_size`.
+    header_size: u64,
+}
+
+impl HeadInfo {
+    pub fn file_ver(&self) -> u32 {
+        self.file_ver
+    }
+
+    pub fn file_cnt(&self) -> u32 {
+        self.file_cnt
+    }
+
+    pub fn index_size(&self) -> u32 {
+        self.index_size
+    }
+
+    pub fn content_size(&self) -> u32 {
+        self.content_size
+    }
+
+    pub fn header_size(&self) -> u64 {
+        self.header_size
+    }
+}
+
+impl fmt::Display for HeadInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "pack version {}, {} files, index 0x{:x} bytes, content 0x{:x} bytes",
+            self.file_ver, self.file_cnt, self.index_size, self.content_size
+        )
+    }
+}
+
+/// How the content of an entry is compressed, stored in the flag field of its
+/// index entry. Packs from the game only use zlib, with a flag of 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "kebab-case"))]
+pub enum Compression {
+    #[default]
+    Zlib,
+    Gzip,
+    RawDeflate,
+    /// Not compressed, only encrypted.
+    Stored,
+}
+
+impl Compression {
+    fn flag(self) -> u32 {
+        match self {
+            Compression::Zlib => 1,
+            Compression::Gzip => 2,
+            Compression::RawDeflate => 3,
+            Compression::Stored => 0,
+        }
+    }
+
+    // unknown flags are read as zlib like before the flag was used
+    fn from_flag(flag: u32) -> Compression {
+        match flag {
+            0 => Compression::Stored,
+            2 => Compression::Gzip,
+            3 => Compression::RawDeflate,
+            _ => Compression::Zlib,
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Compression::Zlib => "zlib",
+            Compression::Gzip => "gzip",
+            Compression::RawDeflate => "raw-deflate",
+            Compression::Stored => "stored",
+        })
+    }
+}
+
+impl FromStr for Compression {
+    type Err = MabiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zlib" => Ok(Compression::Zlib),
+            "gzip" => Ok(Compression::Gzip),
+            "raw-deflate" => Ok(Compression::RawDeflate),
+            "stored" => Ok(Compression::Stored),
+            _ => Err(MabiError::InvalidArgument(format!("compression: {}", s))),
+        }
+    }
+}
+
+/// The encoding of the names in the index. Packs are in utf-8, but some old
+/// packs of the korean and japanese clients are in their codepage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NameEncoding {
+    #[default]
+    Utf8,
+    Cp949,
+    ShiftJis,
+}
+
+impl NameEncoding {
+    fn encoding(self) -> &'static encoding_rs::Encoding {
+        match self {
+            NameEncoding::Utf8 => encoding_rs::UTF_8,
+            // the whatwg euc-kr of encoding_rs is cp949
+            NameEncoding::Cp949 => encoding_rs::EUC_KR,
+            NameEncoding::ShiftJis => encoding_rs::SHIFT_JIS,
+        }
+    }
+
+    fn decode(self, bytes: Vec<u8>) -> Result<String, MabiError> {
+        if self == NameEncoding::Utf8 {
+            return Ok(String::from_utf8(bytes)?);
+        }
+        self.encoding()
+            .decode_without_bom_handling_and_without_replacement(&bytes)
+            .map(|s| s.into_owned())
+            .ok_or_else(|| {
+                MabiError::NameEncoding(self, String::from_utf8_lossy(&bytes).into_owned())
+            })
+    }
+
+    fn encode(self, name: &str) -> Result<Vec<u8>, MabiError> {
+        let (bytes, _, unmappable) = self.encoding().encode(name);
+        if unmappable {
+            return Err(MabiError::NameEncoding(self, name.to_string()));
+        }
+        Ok(bytes.into_owned())
+    }
+}
+
+impl fmt::Display for NameEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            NameEncoding::Utf8 => "utf8",
+            NameEncoding::Cp949 => "cp949",
+            NameEncoding::ShiftJis => "sjis",
+        })
+    }
+}
+
+impl FromStr for NameEncoding {
+    type Err = MabiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf8" => Ok(NameEncoding::Utf8),
+            "cp949" => Ok(NameEncoding::Cp949),
+            "sjis" => Ok(NameEncoding::ShiftJis),
+            _ => Err(MabiError::InvalidArgument(format!("encoding: {}", s))),
+        }
+    }
+}
+
+/// The generator of the key stream encrypting the content. The game uses
+/// MT19937, some other clients reportedly MT19937-64, with the same seed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyGenerator {
+    #[default]
+    Mt19937,
+    Mt19937x64,
+}
+
+impl fmt::Display for KeyGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            KeyGenerator::Mt19937 => "mt19937",
+            KeyGenerator::Mt19937x64 => "mt19937-64",
+        })
+    }
+}
+
+impl FromStr for KeyGenerator {
+    type Err = MabiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mt19937" => Ok(KeyGenerator::Mt19937),
+            "mt19937-64" => Ok(KeyGenerator::Mt19937x64),
+            _ => Err(MabiError::InvalidArgument(format!("key generator: {}", s))),
+        }
+    }
+}
+
+/// Where the seed of the key stream of an entry comes from. The game seeds it
+/// from the version only; the others are experimental, for packs of other
+/// tools giving every file its own key stream: `Name` XORs in the FNV-1a hash
+/// of the UTF-8 name (with `\` separators), `Offset` the offset of the
+/// content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SeedDerivation {
+    #[default]
+    Version,
+    Name,
+    Offset,
+}
+
+impl SeedDerivation {
+    /// The seed of the key stream of an entry.
+    pub fn seed(self, version: u32, name: &str, off: u32) -> u32 {
+        let seed = keystream::seed(version);
+        match self {
+            SeedDerivation::Version => seed,
+            SeedDerivation::Name => seed ^ fnv1a(name.as_bytes()),
+            SeedDerivation::Offset => seed ^ off,
+        }
+    }
+}
+
+// The 32-bit FNV-1a hash.
+fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |h, &b| {
+        (h ^ b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+impl fmt::Display for SeedDerivation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            SeedDerivation::Version => "version",
+            SeedDerivation::Name => "name",
+            SeedDerivation::Offset => "offset",
+        })
+    }
+}
+
+impl FromStr for SeedDerivation {
+    type Err = MabiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "version" => Ok(SeedDerivation::Version),
+            "name" => Ok(SeedDerivation::Name),
+            "offset" => Ok(SeedDerivation::Offset),
+            _ => Err(MabiError::InvalidArgument(format!(
+                "seed derivation: {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// How to read a pack.
+#[derive(Debug, Clone)]
+pub struct ReadOptions {
+    pub encoding: NameEncoding,
+    pub key_generator: KeyGenerator,
+    pub seed_derivation: SeedDerivation,
+    /// Refuse a pack claiming more files than this, `DEFAULT_MAX_ENTRIES` by
+    /// default.
+    pub max_entries: u32,
+    /// Where the index starts, `HEADER_SIZE` by default. The format doesn't
+    /// record it, so variant packs whose header has more or less padding after
+    /// its fields need it set. At least `MIN_HEADER_SIZE`.
+    pub header_size: u64,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            encoding: NameEncoding::default(),
+            key_generator: KeyGenerator::default(),
+            seed_derivation: SeedDerivation::default(),
+            max_entries: DEFAULT_MAX_ENTRIES,
+            header_size: HEADER_SIZE,
+        }
+    }
+}
+
+/// An entry of the index. With the `serde` feature, it serializes with the field
+/// names below, which are kept stable.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct FileInfo {
+    /// The path of the file, with `\` as separator.
+    name: String,
+    /// The version of the file, also the seed of its encryption.
+    version: u32,
+    /// The offset of the content, from the start of the content area.
+    off: u32,
+    /// The size of the stored (compressed and encrypted) content.
+    raw_size: u32,
+    /// The size of the file.
+    uncompr_size: u32,
+    compression: Compression,
+    /// When the file was created, last accessed and modified, as Windows
+    /// FILETIME values (100ns intervals since 1601-01-01 UTC).
+    created: u64,
+    accessed: u64,
+    modified: u64,
+}
+
+impl FileInfo {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn off(&self) -> u32 {
+        self.off
+    }
+
+    pub fn raw_size(&self) -> u32 {
+        self.raw_size
+    }
+
+    pub fn uncompr_size(&self) -> u32 {
+        self.uncompr_size
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    pub fn created(&self) -> u64 {
+        self.created
+    }
+
+    pub fn accessed(&self) -> u64 {
+        self.accessed
+    }
+
+    pub fn modified(&self) -> u64 {
+        self.modified
+    }
+}
+
+impl fmt::Display for FileInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (version {}, {} bytes, stored 0x{:x} bytes at 0x{:x}, {})",
+            self.name, self.version, self.uncompr_size, self.raw_size, self.off, self.compression
+        )
+    }
+}
+
+/// Keeps only the entry with the highest version among the entries sharing a
+/// name, like the game does for files present in several packs. Names are
+/// compared case-insensitively, the last entry wins on equal versions, and the
+/// order of the kept entries is preserved.
+pub(crate) fn latest_entries<T>(entries: Vec<T>, key: impl Fn(&T) -> (&str, u32)) -> Vec<T> {
+    let mut latest: HashMap<String, (usize, u32)> = HashMap::new();
+    for (i, e) in entries.iter().enumerate() {
+        let (name, version) = key(e);
+        let best = latest.entry(name.to_lowercase()).or_insert((i, version));
+        if version >= best.1 {
+            *best = (i, version);
+        }
+    }
+    let mut kept = vec![false; entries.len()];
+    latest.values().for_each(|&(i, _)| kept[i] = true);
+    entries
+        .into_iter()
+        .zip(kept)
+        .filter_map(|(e, keep)| if keep { Some(e) } else { None })
+        .collect()
+}
+
+/// The form names are compared in: case-insensitive like the game, with `/`
+/// accepted as the `\` separator used in packs.
+pub(crate) fn normalize_name(name: &str) -> String {
+    name.replace('/', "\\").to_lowercase()
+}
+
+/// Parses a version, in decimal or in hex with a `0x` prefix.
+pub(crate) fn parse_version(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse::<u32>().ok(),
+    }
+}
+
+/// Converts a glob pattern to an anchored, case-insensitive regular expression
+/// matching names in the pack. `*` and `?` don't cross folders while `**` does,
+/// and `/` matches the `\` separator used in packs.
+pub(crate) fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from("(?i)^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                re.push_str(".*");
+            }
+            '*' => re.push_str(r"[^\\]*"),
+            '?' => re.push_str(r"[^\\]"),
+            '/' | '\\' => re.push_str(r"\\"),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
+fn read_c_str(mut stm: Vec<u8>, encoding: NameEncoding) -> Result<String, MabiError> {
+    let len = stm
+        .iter()
+        .position(|&c| c == 0)
+        .ok_or(MabiError::WrongFormat)?;
+    stm.resize(len, 0);
+    encoding.decode(stm)
+}
+
+// The reverse of `write_str_block`, see `calc_str_size` for the sizes.
+fn read_str(stm: &mut impl Read, encoding: NameEncoding) -> Result<String, MabiError> {
+    let str_size = match stm.read_u8()? as usize {
+        n @ 0..=3 => (n + 1) * 16 - 1,
+        4 => 6 * 16 - 1,
+        5 => stm.read_u32::<LittleEndian>()? as usize,
+        _ => return Err(MabiError::WrongFormat),
+    };
+    // don't trust the size before the bytes are actually there
+    let mut s: Vec<u8> = vec![];
+    stm.take(str_size as u64).read_to_end(&mut s)?;
+    if s.len() != str_size {
+        return Err(MabiError::WrongFormat);
+    }
+    read_c_str(s, encoding)
+}
+
+pub(crate) fn check_header_size(header_size: u64) -> Result<(), MabiError> {
+    if header_size < MIN_HEADER_SIZE {
+        return Err(MabiError::InvalidArgument(format!(
+            "header size: 0x{:x}, the fields of the header end at 0x{:x}",
+            header_size, MIN_HEADER_SIZE
+        )));
+    }
+    Ok(())
+}
+
+pub fn read_header(stm: &mut (impl Read + Seek)) -> Result<HeadInfo, MabiError> {
+    read_header_with(stm, &ReadOptions::default())
+}
+
+/// Like `read_header`, for a header of `options.header_size` bytes.
+pub fn read_header_with(
+    stm: &mut (impl Read + Seek),
+    options: &ReadOptions,
+) -> Result<HeadInfo, MabiError> {
+    let header_size = options.header_size;
+    check_header_size(header_size)?;
+    let magic = stm.read_u32::<LittleEndian>()?;
+    let pack_ver = stm.read_u32::<LittleEndian>()?;
+    if magic != 0x4b434150 || pack_ver != 0x102 {
+        return Err(MabiError::WrongFormat);
+    }
+    let file_ver = stm.read_u32::<LittleEndian>()?;
+    let file_cnt = stm.read_u32::<LittleEndian>()?;
+    stm.seek(SeekFrom::Current(0x1f0))?;
+
+    if stm.read_u32::<LittleEndian>()? != file_cnt {
+        return Err(MabiError::WrongFormat);
+    }
+    let index_size = stm.read_u32::<LittleEndian>()?;
+    stm.seek(SeekFrom::Current(4))?;
+    let content_size = stm.read_u32::<LittleEndian>()?;
+
+    // don't loop over billions of entries a damaged or hostile header claims
+    if file_cnt as u64 * MIN_ENTRY_SIZE > index_size as u64 {
+        return Err(MabiError::ReadHeaderFail(format!(
+            "{} files can't fit in an index of 0x{:x} bytes, try `repair`",
+            file_cnt, index_size
+        )));
+    }
+    let pos = stm.stream_position()?;
+    let file_len = stm.seek(SeekFrom::End(0))?;
+    stm.seek(SeekFrom::Start(pos))?;
+    if header_size + index_size as u64 > file_len {
+        return Err(MabiError::ReadHeaderFail(format!(
+            "the index of 0x{:x} bytes goes past the end of the file",
+            index_size
+        )));
+    }
+
+    Ok(HeadInfo {
+        file_ver,
+        file_cnt,
+        index_size,
+        content_size,
+        header_size,
+    })
+}
+
+pub(crate) fn read_entry(
+    stm: &mut (impl Read + Seek),
+    encoding: NameEncoding,
+) -> Result<FileInfo, MabiError> {
+    let name = read_str(stm, encoding)?;
+    let version = stm.read_u32::<LittleEndian>()?;
+    stm.seek(SeekFrom::Current(4))?;
+    let off = stm.read_u32::<LittleEndian>()?;
+    let raw_size = stm.read_u32::<LittleEndian>()?;
+    let uncompr_size = stm.read_u32::<LittleEndian>()?;
+    let compression = Compression::from_flag(stm.read_u32::<LittleEndian>()?);
+    // created and modified are stored twice
+    let created = stm.read_u64::<LittleEndian>()?;
+    stm.seek(SeekFrom::Current(8))?;
+    let accessed = stm.read_u64::<LittleEndian>()?;
+    let modified = stm.read_u64::<LittleEndian>()?;
+    stm.seek(SeekFrom::Current(8))?;
+    Ok(FileInfo {
+        name,
+        version,
+        off,
+        raw_size,
+        uncompr_size,
+        compression,
+        created,
+        accessed,
+        modified,
+    })
+}
+
+pub fn read_index(
+    stm: &mut (impl Read + Seek),
+    head_info: &HeadInfo,
+) -> Result<Vec<FileInfo>, MabiError> {
+    read_index_with(stm, head_info, &ReadOptions::default())
+}
+
+pub fn read_index_with(
+    stm: &mut (impl Read + Seek),
+    head_info: &HeadInfo,
+    options: &ReadOptions,
+) -> Result<Vec<FileInfo>, MabiError> {
+    if head_info.file_cnt > options.max_entries {
+        return Err(MabiError::ReadHeaderFail(format!(
+            "{} files, more than the limit of {}",
+            head_info.file_cnt, options.max_entries
+        )));
+    }
+    stm.seek(SeekFrom::Start(head_info.header_size))?;
+    // don't trust the size before the bytes are actually there
+    let mut index: Vec<u8> = vec![];
+    stm.take(head_info.index_size as u64)
+        .read_to_end(&mut index)
+        .map_err(|e| MabiError::ReadIndexFail(e.to_string()))?;
+    if index.len() != head_info.index_size as usize {
+        return Err(MabiError::ReadIndexFail("index truncated".to_string()));
+    }
+    let mut index = Cursor::new(index);
+    let mut files: Vec<FileInfo> = vec![];
+    for i in 0..head_info.file_cnt {
+        // with a wrong file_cnt in the header, the entries past the real ones
+        // are garbage, so stop at the first one which can't be right
+        let fail = |reason: String| {
+            MabiError::ReadIndexFail(format!(
+                "entry {} of {}: {}, the header may be damaged, try `repair`",
+                i, head_info.file_cnt, reason
+            ))
+        };
+        let fi = read_entry(&mut index, options.encoding).map_err(|e| fail(e.to_string()))?;
+        if fi.off as u64 + fi.raw_size as u64 > head_info.content_size as u64 {
+            return Err(fail(format!(
+                "content at 0x{:x} of 0x{:x} bytes is beyond the content size",
+                fi.off, fi.raw_size
+            )));
+        }
+        files.push(fi);
+    }
+    Ok(files)
+}
+
+/// Opens a pack to read it. On Windows, other programs are explicitly let to
+/// keep reading, writing or deleting it, like the game while it runs. The game
+/// may still not let others read a pack it has open, which fails with a
+/// sharing violation.
+pub fn open_pack(path: impl AsRef<Path>) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true);
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        // FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE
+        options.share_mode(0x7);
+    }
+    options.open(path).map_err(|e| {
+        // ERROR_SHARING_VIOLATION
+        if cfg!(windows) && e.raw_os_error() == Some(32) {
+            io::Error::new(
+                e.kind(),
+                format!("{}, the program using it doesn't let others read it", e),
+            )
+        } else {
+            e
+        }
+    })
+}
+
+/// Reads the header and the index of a pack.
+pub fn list_entries(path: &str) -> Result<(HeadInfo, Vec<FileInfo>), MabiError> {
+    let fs = open_pack(path)?;
+    let mut reader = BufReader::new(fs);
+    let head_info = read_header(&mut reader)?;
+    let file_entries = read_index(&mut reader, &head_info)?;
+    Ok((head_info, file_entries))
+}
+
+/// A fresh folder under the temp dir for a test, removed first if a previous
+/// run left it.
+#[cfg(test)]
+pub(crate) fn temp_folder(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("mabi-pack-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
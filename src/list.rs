@@ -1,34 +1,409 @@
-use super::{read_header, read_index, MabiError};
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader, Write};
+use super::extract::{make_regex, matches_filters, read_file_head};
+use super::filetime::to_iso8601;
+use super::json::Object;
+use super::{
+    latest_entries, normalize_name, open_pack, read_entry, read_header_with, read_index_with,
+    FileInfo, MabiError, ReadOptions,
+};
+use std::borrow::Cow;
+use std::fs::OpenOptions;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
 
-pub fn run_list(fname: &str, output: Option<&str>, has_version: bool) -> Result<(), MabiError> {
-    let fs = File::open(fname)?;
-    //let tra:Box<dyn Write> = Box::new(fs);
-    let mut reader = BufReader::new(fs);
-    let head_info = read_header(&mut reader)?;
-    let file_entries = read_index(&mut reader, &head_info)?;
-
-    let output_stream: Result<Box<dyn Write>, MabiError> =
-        output.map_or(Ok(Box::new(io::stdout())), |path| {
-            OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(path)
-                .map(|f| Box::new(f) as Box<dyn Write>)
-                .map_err(|e| MabiError::IoFail(e))
-        });
+/// How `run_list` prints the files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ListFormat {
+    /// One name per line, with the fields asked for before it.
+    #[default]
+    Text,
+    /// A JSON array of objects with all the fields of the entries, including
+    /// their timestamps.
+    Json,
+}
 
-    let mut output_stream = output_stream?;
+impl FromStr for ListFormat {
+    type Err = MabiError;
 
-    if !has_version {
-        file_entries.iter().for_each(|e| {
-            writeln!(output_stream, "{}", e.name).unwrap();
-        });
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ListFormat::Text),
+            "json" => Ok(ListFormat::Json),
+            _ => Err(MabiError::InvalidArgument(format!("format: {}", s))),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ListOptions {
+    /// Print the version of every file before its name.
+    pub with_version: bool,
+    /// Stop after this many files.
+    pub limit: Option<usize>,
+    /// When listing a folder, only keep the highest version of files found in
+    /// several packs.
+    pub merge: bool,
+    /// Only keep the highest version of files present several times in the
+    /// pack, like the game does.
+    pub latest: bool,
+    /// Print the type of every file, guessed from its first bytes.
+    pub detect_type: bool,
+    /// Print where every file is stored before its name: its absolute offset in
+    /// the pack and its offset in the content in hex, then its stored and
+    /// uncompressed sizes.
+    pub layout: bool,
+    pub read_options: ReadOptions,
+    /// Only list the files matching any of these regular expressions.
+    pub filters: Vec<String>,
+    /// List the files not matching the filters instead.
+    pub invert_filter: bool,
+    /// End every line with a NUL character instead of a newline, for names
+    /// containing newlines, e.g. to pipe them to `extract --names-from - -0`.
+    pub print0: bool,
+    /// Print the files as text or JSON. With JSON, every field is printed, so
+    /// `with_version` and `layout` have no effect.
+    pub format: ListFormat,
+    /// Print the names with `/` instead of the `\` of the pack, e.g. for URLs.
+    pub unix_paths: bool,
+}
+
+impl ListOptions {
+    fn line_end(&self) -> char {
+        if self.print0 {
+            '\0'
+        } else {
+            '\n'
+        }
+    }
+
+    fn display_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if self.unix_paths {
+            Cow::Owned(name.replace('\\', "/"))
+        } else {
+            Cow::Borrowed(name)
+        }
+    }
+
+    fn keep_matching(&self, file_entries: &mut Vec<FileInfo>) -> Result<(), MabiError> {
+        let filters = make_regex(self.filters.iter().map(|s| s.as_str()).collect())?;
+        file_entries.retain(|e| matches_filters(&filters, self.invert_filter, &e.name));
+        Ok(())
+    }
+}
+
+const SNIFF_SIZE: u64 = 16;
+
+const MAGICS: &[(&[u8], &str)] = &[
+    (b"DDS ", "dds"),
+    (b"\x89PNG", "png"),
+    (b"\xff\xd8\xff", "jpeg"),
+    (b"GIF8", "gif"),
+    (b"BM", "bmp"),
+    (b"OggS", "ogg"),
+    (b"RIFF", "riff"),
+    (b"ID3", "mp3"),
+    (b"PK\x03\x04", "zip"),
+    (b"\x1bLua", "lua"),
+    (b"MZ", "exe"),
+    (b"<?xml", "xml"),
+    (b"\xef\xbb\xbf<?xml", "xml"),
+    (b"\xff\xfe<\x00?\x00x\x00m\x00l\x00", "xml"),
+    (b"\xff\xfe", "text"),
+    (b"\xfe\xff", "text"),
+];
+
+/// Guesses the type of a file from its first bytes.
+fn detect_type(head: &[u8]) -> &'static str {
+    if head.is_empty() {
+        return "empty";
+    }
+    if let Some((_, kind)) = MAGICS.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return kind;
+    }
+    // the head may end in the middle of a utf-8 sequence
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).unwrap(),
+        Err(_) => return "unknown",
+    };
+    if text.chars().all(|c| !c.is_control() || c.is_whitespace()) {
+        "text"
     } else {
-        file_entries.iter().for_each(|e| {
-            writeln!(output_stream, "{} {}", e.version, e.name).unwrap();
+        "unknown"
+    }
+}
+
+fn read_pack(fname: &str, read_options: &ReadOptions) -> Result<Vec<FileInfo>, MabiError> {
+    let fs = open_pack(fname)?;
+    let mut reader = BufReader::new(fs);
+    let head_info = read_header_with(&mut reader, read_options)?;
+    read_index_with(&mut reader, &head_info, read_options)
+}
+
+fn open_output(output: Option<&str>) -> Result<Box<dyn Write>, MabiError> {
+    output.map_or(Ok(Box::new(io::stdout())), |path| {
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .map(|f| Box::new(f) as Box<dyn Write>)
+            .map_err(MabiError::IoFail)
+    })
+}
+
+pub fn run_list(fname: &str, output: Option<&str>, options: &ListOptions) -> Result<(), MabiError> {
+    let mut output_stream = open_output(output)?;
+    list_to_writer(fname, &mut output_stream, options)
+}
+
+/// Like `run_list`, writing to a sink the caller manages instead, e.g. a pipe
+/// or a `Vec<u8>`.
+pub fn list_to_writer(
+    fname: &str,
+    output_stream: &mut dyn Write,
+    options: &ListOptions,
+) -> Result<(), MabiError> {
+    let fs = open_pack(fname)?;
+    let mut reader = BufReader::new(fs);
+    let head_info = read_header_with(&mut reader, &options.read_options)?;
+    let mut file_entries = read_index_with(&mut reader, &head_info, &options.read_options)?;
+    options.keep_matching(&mut file_entries)?;
+    if options.latest {
+        file_entries = latest_entries(file_entries, |e| (&e.name, e.version));
+    }
+
+    let content_start = head_info.header_size + head_info.index_size as u64;
+    let count = file_entries.len().min(options.limit.unwrap_or(usize::MAX));
+    let json = options.format == ListFormat::Json;
+    if json {
+        writeln!(output_stream, "[")?;
+    }
+    for (n, e) in file_entries.iter().take(count).enumerate() {
+        let kind = options.detect_type.then(|| {
+            read_file_head(
+                &mut reader,
+                &head_info,
+                e,
+                SNIFF_SIZE,
+                &options.read_options,
+            )
+            .map_or("corrupted", |head| detect_type(&head))
         });
+        if json {
+            let name = options.display_name(&e.name);
+            write_json_entry(output_stream, &name, e, content_start, kind, n + 1 == count)?;
+            continue;
+        }
+        if options.layout {
+            write!(
+                output_stream,
+                "{:08x} {:08x} {:10} {:10} ",
+                content_start + e.off as u64,
+                e.off,
+                e.raw_size,
+                e.uncompr_size
+            )?;
+        }
+        if options.with_version {
+            write!(output_stream, "{} ", e.version)?;
+        }
+        if let Some(kind) = kind {
+            write!(output_stream, "{} ", kind)?;
+        }
+        write!(
+            output_stream,
+            "{}{}",
+            options.display_name(&e.name),
+            options.line_end()
+        )?;
+    }
+    if json {
+        writeln!(output_stream, "]")?;
+    }
+    Ok(())
+}
+
+// An entry of the JSON list, its timestamps both in ISO 8601 UTC and as raw
+// FILETIME values.
+fn write_json_entry(
+    out: &mut dyn Write,
+    name: &str,
+    e: &FileInfo,
+    content_start: u64,
+    kind: Option<&str>,
+    last: bool,
+) -> io::Result<()> {
+    let mut entry = Object::new()
+        .string("name", name)
+        .value("version", e.version)
+        .value("pack_offset", content_start + e.off as u64)
+        .value("offset", e.off)
+        .value("raw_size", e.raw_size)
+        .value("size", e.uncompr_size)
+        .string("compression", &e.compression.to_string());
+    if let Some(kind) = kind {
+        entry = entry.string("type", kind);
+    }
+    for (field, t) in [
+        ("created", e.created),
+        ("accessed", e.accessed),
+        ("modified", e.modified),
+    ] {
+        entry = entry
+            .string(field, &to_iso8601(t))
+            .value(&format!("{}_filetime", field), t);
+    }
+    writeln!(out, "  {}{}", entry, if last { "" } else { "," })
+}
+
+// Like xxd: the offset in the pack, 16 bytes in hex, then as ascii.
+fn write_hex_dump(out: &mut impl Write, start: u64, bytes: &[u8]) -> io::Result<()> {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        write!(out, "{:08x}:", start + i as u64 * 16)?;
+        for b in line {
+            write!(out, " {:02x}", b)?;
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        writeln!(out, "{}  {}", "   ".repeat(16 - line.len()), ascii)?;
+    }
+    Ok(())
+}
+
+/// Prints the raw index entries of the files with this name as a hex dump: the
+/// string block, then the 0x40 bytes of fields, including those the parser
+/// skips (the unknown field after the version and the timestamps).
+pub fn run_dump_entry(
+    fname: &str,
+    name: &str,
+    output: Option<&str>,
+    read_options: &ReadOptions,
+) -> Result<(), MabiError> {
+    let fs = open_pack(fname)?;
+    let mut reader = BufReader::new(fs);
+    let head_info = read_header_with(&mut reader, read_options)?;
+    // check the whole index first, then walk it again keeping the offsets
+    read_index_with(&mut reader, &head_info, read_options)?;
+    reader.seek(SeekFrom::Start(head_info.header_size))?;
+    let wanted = normalize_name(name);
+    let mut output_stream = open_output(output)?;
+    let mut found = false;
+    for _ in 0..head_info.file_cnt {
+        let start = reader.stream_position()?;
+        let fi = read_entry(&mut reader, read_options.encoding)?;
+        let end = reader.stream_position()?;
+        if normalize_name(&fi.name) != wanted {
+            continue;
+        }
+        found = true;
+        let mut raw = vec![0; (end - start) as usize];
+        reader.seek(SeekFrom::Start(start))?;
+        reader.read_exact(&mut raw)?;
+        writeln!(
+            output_stream,
+            "{} at 0x{:x}, 0x{:x} bytes:",
+            fi.name,
+            start,
+            raw.len()
+        )?;
+        write_hex_dump(&mut output_stream, start, &raw)?;
+    }
+    if !found {
+        return Err(MabiError::NotFound(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Reads the indexes of every `.pack` in a folder concurrently. Returns the
+/// file name of every pack with its files, sorted by pack name.
+pub(crate) fn read_packs_in_dir(
+    folder: &str,
+    read_options: &ReadOptions,
+) -> Result<Vec<(String, Vec<FileInfo>)>, MabiError> {
+    let mut packs: Vec<String> = std::fs::read_dir(folder)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && p.extension().is_some_and(|ext| ext == "pack"))
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect();
+    packs.sort();
+
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = packs.len().div_ceil(workers).max(1);
+    let indexes = thread::scope(|s| {
+        let handles: Vec<_> = packs
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|p| {
+                            read_pack(p, read_options)
+                                .map_err(|e| MabiError::ListFail(p.clone(), e.to_string()))
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect::<Result<Vec<_>, MabiError>>()
+    })?;
+
+    Ok(packs
+        .iter()
+        .map(|p| {
+            Path::new(p)
+                .file_name()
+                .map_or(p.clone(), |n| n.to_string_lossy().into_owned())
+        })
+        .zip(indexes)
+        .collect())
+}
+
+/// Lists the files of every `.pack` in a folder, each line prefixed with the
+/// name of the pack. The indexes are read concurrently.
+pub fn run_list_dir(
+    folder: &str,
+    output: Option<&str>,
+    options: &ListOptions,
+) -> Result<(), MabiError> {
+    let mut file_entries: Vec<(String, FileInfo)> = vec![];
+    for (pack_name, mut entries) in read_packs_in_dir(folder, &options.read_options)? {
+        options.keep_matching(&mut entries)?;
+        file_entries.extend(entries.into_iter().map(|e| (pack_name.clone(), e)));
+    }
+    if options.merge {
+        file_entries = latest_entries(file_entries, |(_, e)| (&e.name, e.version));
+    }
+
+    let mut output_stream = open_output(output)?;
+    for (pack_name, e) in file_entries
+        .iter()
+        .take(options.limit.unwrap_or(usize::MAX))
+    {
+        if options.with_version {
+            write!(output_stream, "{} {} ", pack_name, e.version)?;
+        } else {
+            write!(output_stream, "{} ", pack_name)?;
+        }
+        write!(
+            output_stream,
+            "{}{}",
+            options.display_name(&e.name),
+            options.line_end()
+        )?;
     }
     Ok(())
 }
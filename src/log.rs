@@ -0,0 +1,41 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Hides the diagnostics which aren't errors, e.g. warnings and summaries.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub(crate) fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Also prints the details of what is done, e.g. how files are decoded.
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+pub(crate) fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::Relaxed) && !is_quiet()
+}
+
+/// Prints a diagnostic to stderr, keeping stdout for the actual output of a
+/// command, unless quiet.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if !$crate::log::is_quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Prints a detail to stderr, only if verbose.
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if $crate::log::is_verbose() {
+            eprintln!($($arg)*);
+        }
+    };
+}
@@ -1,159 +1,92 @@
-use byteorder::{LittleEndian, ReadBytesExt};
-use clap::{App, Arg, SubCommand};
-use std::fs::File;
-use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
-use thiserror::Error as ThisError;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use mabi_pack::eol::TextEol;
+use mabi_pack::{
+    describe, diff, extract, info, list, pack, repair, verify, which, MabiError, ReadOptions,
+    DEFAULT_MAX_ENTRIES, HEADER_SIZE,
+};
+use std::io;
+use std::str::FromStr;
 
-mod extract;
-mod list;
-mod pack;
-
-pub const HEADER_SIZE: u64 = 0x220;
-
-#[derive(ThisError, Debug)]
-pub enum MabiError {
-    #[error("io error: {0}")]
-    IoFail(#[from] std::io::Error),
-
-    #[error("format error")]
-    WrongFormat,
-
-    #[error("encoding error")]
-    Encoding(#[from] std::string::FromUtf8Error),
-
-    #[error("corrupted file")]
-    CorruptedFile,
-
-    #[error("unknown file path: {0}")]
-    UnrecognizedPath(String),
-
-    #[error("regular expression invalid: {0}")]
-    InvalidRegexp(String),
-
-    #[error("invalid version")]
-    InvalidVersion,
-
-    #[error("compress error: {0}")]
-    CompressError(String),
-
-    #[error("file time error")]
-    TimeError,
-
-    #[error("reading header fail: {0}")]
-    ReadHeaderFail(String),
-
-    #[error("reading index fail: {0}")]
-    ReadIndexFail(String),
-
-    #[error("error when extracting file:{0}, {1}")]
-    ExtractFail(String, String),
-
-    #[error("error in traversing the folder: {0}")]
-    TraversingFail(String),
-
-    #[error("error in processing:{0}, {1}")]
-    PackingFail(String, String),
-
-    #[error("Internal error")]
-    InternalError,
-}
-
-#[derive(Debug)]
-pub struct HeadInfo {
-    file_ver: u32,
-    file_cnt: u32,
-    index_size: u32,
-    content_size: u32,
-}
-
-#[derive(Debug)]
-pub struct FileInfo {
-    name: String,
-    version: u32,
-    off: u32,
-    raw_size: u32,
-    uncompr_size: u32,
+fn parse_arg<T: FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>, MabiError> {
+    matches
+        .value_of(name)
+        .map(|v| {
+            v.parse::<T>()
+                .map_err(|_| MabiError::InvalidArgument(format!("{}: {}", name, v)))
+        })
+        .transpose()
 }
 
-fn read_c_str(mut stm: Vec<u8>) -> Result<String, MabiError> {
-    let len = stm
-        .iter()
-        .position(|&c| c == 0)
-        .ok_or(MabiError::WrongFormat)?;
-    stm.resize(len, 0);
-    String::from_utf8(stm).map_err(|e| MabiError::Encoding(e))
+// Unix permissions in octal, e.g. 644 or 0644.
+fn parse_mode(matches: &ArgMatches, name: &str) -> Result<Option<u32>, MabiError> {
+    matches
+        .value_of(name)
+        .map(|v| {
+            u32::from_str_radix(v.trim_start_matches("0o"), 8)
+                .ok()
+                .filter(|&mode| mode <= 0o7777)
+                .ok_or_else(|| MabiError::InvalidArgument(format!("{}: {}", name, v)))
+        })
+        .transpose()
 }
 
-fn read_str(stm: &mut impl Read) -> Result<String, MabiError> {
-    let str_size = match stm.read_u8()? as usize {
-        n @ 0..=3 => (n + 1) * 16 - 1,
-        4 => 6 * 16 - 1,
-        5 => stm.read_u32::<LittleEndian>()? as usize,
-        _ => return Err(MabiError::WrongFormat),
-    };
-    //@todo optimize this!
-    let mut s: Vec<u8> = vec![0; str_size];
-    stm.read_exact(&mut s)?;
-    read_c_str(s)
+// A size in bytes, in decimal or in hexadecimal with 0x like offsets are printed.
+fn parse_size(matches: &ArgMatches, name: &str) -> Result<Option<u64>, MabiError> {
+    matches
+        .value_of(name)
+        .map(|v| {
+            match v.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => v.parse(),
+            }
+            .map_err(|_| MabiError::InvalidArgument(format!("{}: {}", name, v)))
+        })
+        .transpose()
 }
 
-pub fn read_header(stm: &mut BufReader<File>) -> Result<HeadInfo, MabiError> {
-    let magic = stm.read_u32::<LittleEndian>()?;
-    let pack_ver = stm.read_u32::<LittleEndian>()?;
-    if magic != 0x4b434150 || pack_ver != 0x102 {
-        return Err(MabiError::WrongFormat);
-    }
-    let file_ver = stm.read_u32::<LittleEndian>()?;
-    let file_cnt = stm.read_u32::<LittleEndian>()?;
-    stm.seek(SeekFrom::Current(0x1f0))?;
-
-    if stm.read_u32::<LittleEndian>()? != file_cnt {
-        return Err(MabiError::WrongFormat);
-    }
-    let index_size = stm.read_u32::<LittleEndian>()?;
-    stm.seek(SeekFrom::Current(4))?;
-    let content_size = stm.read_u32::<LittleEndian>()?;
-
-    Ok(HeadInfo {
-        file_ver,
-        file_cnt,
-        index_size,
-        content_size,
+fn read_options(matches: &ArgMatches) -> Result<ReadOptions, MabiError> {
+    Ok(ReadOptions {
+        encoding: parse_arg(matches, "encoding")?.unwrap_or_default(),
+        key_generator: parse_arg(matches, "key-generator")?.unwrap_or_default(),
+        seed_derivation: parse_arg(matches, "seed-from")?.unwrap_or_default(),
+        max_entries: parse_arg(matches, "max-entries")?.unwrap_or(DEFAULT_MAX_ENTRIES),
+        header_size: parse_size(matches, "header-size")?.unwrap_or(HEADER_SIZE),
     })
 }
 
-pub fn read_index(
-    stm: &mut BufReader<File>,
-    head_info: &HeadInfo,
-) -> Result<Vec<FileInfo>, MabiError> {
-    stm.seek(SeekFrom::Start(HEADER_SIZE))?;
-    let mut index: Vec<u8> = vec![0; head_info.index_size as usize];
-    stm.read_exact(&mut index)?;
-    let mut index = Cursor::new(index);
-    let mut files: Vec<FileInfo> = vec![];
-    for _ in 0..head_info.file_cnt {
-        let name = read_str(&mut index)?;
-        let version = index.read_u32::<LittleEndian>()?;
-        index.seek(SeekFrom::Current(4))?;
-        let off = index.read_u32::<LittleEndian>()?;
-        let raw_size = index.read_u32::<LittleEndian>()?;
-        let uncompr_size = index.read_u32::<LittleEndian>()?;
-        index.seek(SeekFrom::Current(0x2c))?;
-        files.push(FileInfo {
-            name,
-            version,
-            off,
-            raw_size,
-            uncompr_size,
-        });
-    }
-    Ok(files)
+fn text_eol(matches: &ArgMatches) -> Result<Option<TextEol>, MabiError> {
+    parse_arg(matches, "text-eol")?
+        .map(|eol| {
+            let globs: Vec<&str> = matches.values_of("text-glob").unwrap().collect();
+            TextEol::new(eol, &globs)
+        })
+        .transpose()
 }
 
 fn main() {
     let args = App::new("Mabinogi pack utilities")
         .version("1.1.1")
         .author("regomne <fallingsunz@gmail.com>")
+        .arg(
+            Arg::with_name("json-errors")
+                .long("json-errors")
+                .help("Print errors as JSON objects on stderr")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Only print errors on stderr")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("Print details of what is done on stderr")
+                .global(true),
+        )
         .subcommand(
             SubCommand::with_name("pack")
                 .about("Create a pack")
@@ -163,14 +96,14 @@ fn main() {
                         .long("input")
                         .value_name("FOLDER")
                         .help("Set the input folder to pack")
-                        .required(true),
+                        .required_unless("from-stream"),
                 )
                 .arg(
                     Arg::with_name("output")
                         .short("o")
                         .long("output")
                         .value_name("PACK_NAME")
-                        .help("Set the output .pack file name")
+                        .help("Set the output .pack file name, - to write it to stdout")
                         .required(true),
                 )
                 .arg(
@@ -178,8 +111,202 @@ fn main() {
                         .short("k")
                         .long("key-version")
                         .value_name("VER_KEY")
-                        .help("Set the version (and will be used as a seed)")
+                        .help("Set the version (and will be used as a seed), in decimal or 0x-prefixed hex")
                         .required(true),
+                )
+                .arg(
+                    Arg::with_name("dedupe")
+                        .long("dedupe")
+                        .help("Store identical files only once, sharing their content in the pack"),
+                )
+                .arg(
+                    Arg::with_name("root-name")
+                        .long("root-name")
+                        .value_name("ROOT")
+                        .help("Set the root path written in the header, defaults to data\\"),
+                )
+                .arg(
+                    Arg::with_name("header-size")
+                        .long("header-size")
+                        .value_name("BYTES")
+                        .help("Pad the header with zeros to BYTES bytes instead of 0x220, for variant packs"),
+                )
+                .arg(
+                    Arg::with_name("compression")
+                        .long("compression")
+                        .value_name("FORMAT")
+                        .help("Set how files are compressed, only zlib is supported by the game")
+                        .possible_values(&["zlib", "gzip", "raw-deflate", "stored"])
+                        .default_value("zlib"),
+                )
+                .arg(
+                    Arg::with_name("store-incompressible")
+                        .long("store-incompressible")
+                        .value_name("PERCENT")
+                        .help("Store files uncompressed when compressing them saves less than PERCENT% of their size"),
+                )
+                .arg(
+                    Arg::with_name("record-empty-dirs")
+                        .long("record-empty-dirs")
+                        .help("Store empty folders as zero-length entries whose name ends with \\"),
+                )
+                .arg(
+                    Arg::with_name("no-hidden")
+                        .long("no-hidden")
+                        .help("Leave out hidden files and folders, whose name starts with . or hidden on Windows"),
+                )
+                .arg(
+                    Arg::with_name("no-follow-junctions")
+                        .long("no-follow-junctions")
+                        .help("Leave out symbolic links and junctions instead of following them"),
+                )
+                .arg(
+                    Arg::with_name("skip-empty")
+                        .long("skip-empty")
+                        .help("Leave out the files of 0 bytes"),
+                )
+                .arg(
+                    Arg::with_name("warn-empty")
+                        .long("warn-empty")
+                        .help("Print the files of 0 bytes, skipped with --skip-empty"),
+                )
+                .arg(
+                    Arg::with_name("sync")
+                        .long("sync")
+                        .help("Flush the pack to the disk (fsync) before exiting"),
+                )
+                .arg(
+                    Arg::with_name("temp-dir")
+                        .long("temp-dir")
+                        .value_name("DIR")
+                        .help("Build the pack in DIR before moving it to the output, instead of next to the output"),
+                )
+                .arg(
+                    Arg::with_name("with-trailer")
+                        .long("with-trailer")
+                        .help("Append a trailer with the sha256 of the pack, checked by verify --fast"),
+                )
+                .arg(
+                    Arg::with_name("verify")
+                        .long("verify")
+                        .help("Read the pack back once written, and check that a sample of its files decode to their source"),
+                )
+                .arg(
+                    Arg::with_name("verify-full")
+                        .long("verify-full")
+                        .help("Like --verify, checking all the files"),
+                )
+                .arg(
+                    Arg::with_name("progress-json")
+                        .long("progress-json")
+                        .help("Report the progress on stderr as JSON lines, e.g. for a GUI"),
+                )
+                .arg(
+                    Arg::with_name("report-json")
+                        .long("report-json")
+                        .value_name("FILE")
+                        .help("Write the header, the sha256 of the pack and of every file to FILE as JSON, e.g. to compare builds in CI"),
+                )
+                .arg(
+                    Arg::with_name("pad-to")
+                        .long("pad-to")
+                        .value_name("BYTES")
+                        .help("Pad the pack with zeros to exactly BYTES"),
+                )
+                .arg(
+                    Arg::with_name("from-list")
+                        .long("from-list")
+                        .value_name("LIST_FILE_NAME")
+                        .help("Only pack the files of the list, in its order. Lines are \"NAME\" or \"VERSION NAME\" as printed by list --with-version"),
+                )
+                .arg(
+                    Arg::with_name("from-stream")
+                        .long("from-stream")
+                        .value_name("STREAM")
+                        .help("Pack the files of a stream of size-prefixed names and contents instead of a folder, - for stdin")
+                        .conflicts_with_all(&[
+                            "input",
+                            "from-list",
+                            "dedupe",
+                            "header-size",
+                            "store-incompressible",
+                            "record-empty-dirs",
+                            "no-hidden",
+                            "no-follow-junctions",
+                            "skip-empty",
+                            "warn-empty",
+                            "verify",
+                            "verify-full",
+                            "report-json",
+                            "validate-names",
+                            "continue-on-error",
+                            "text-eol",
+                        ]),
+                )
+                .arg(
+                    Arg::with_name("on-missing")
+                        .long("on-missing")
+                        .value_name("POLICY")
+                        .help("Set what to do with files of the --from-list list which don't exist")
+                        .possible_values(&["error", "skip"])
+                        .default_value("error"),
+                )
+                .arg(
+                    Arg::with_name("validate-names")
+                        .long("validate-names")
+                        .help("Check that the game can load all the names before packing anything"),
+                )
+                .arg(
+                    Arg::with_name("continue-on-error")
+                        .long("continue-on-error")
+                        .help("Leave out the files which can't be read, e.g. locked, instead of failing"),
+                )
+                .arg(
+                    Arg::with_name("times-fallback")
+                        .long("times-fallback")
+                        .value_name("POLICY")
+                        .help("Set what to store when the system can't tell the access or modified time of a file")
+                        .possible_values(&["modified", "now", "zero"])
+                        .default_value("modified"),
+                )
+                .arg(
+                    Arg::with_name("text-eol")
+                        .long("text-eol")
+                        .value_name("EOL")
+                        .help("Convert the line endings of the text files matching --text-glob when packing")
+                        .possible_values(&["lf", "crlf"])
+                        .requires("text-glob"),
+                )
+                .arg(
+                    Arg::with_name("text-glob")
+                        .long("text-glob")
+                        .value_name("GLOB")
+                        .help("Set the text files for --text-eol, e.g. *.xml, multiple occurrences mean OR")
+                        .number_of_values(1)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("key-generator")
+                        .long("key-generator")
+                        .value_name("GENERATOR")
+                        .help("Set the generator of the key stream encrypting the content, mt19937-64 for some other clients")
+                        .possible_values(&["mt19937", "mt19937-64"])
+                        .default_value("mt19937"),
+                )
+                .arg(
+                    Arg::with_name("seed-from")
+                        .long("seed-from")
+                        .value_name("SOURCE")
+                        .help("Experimental: also seed the key stream of every file from its name or its offset, like packs of some other tools; version by default, like the game")
+                        .possible_values(&["version", "name", "offset"]),
+                )
+                .arg(
+                    Arg::with_name("encoding")
+                        .long("encoding")
+                        .value_name("ENCODING")
+                        .help("Set the encoding of file names in the pack, cp949 or sjis for some korean or japanese clients")
+                        .possible_values(&["utf8", "cp949", "sjis"])
+                        .default_value("utf8"),
                 ),
         )
         .subcommand(
@@ -198,8 +325,7 @@ fn main() {
                         .short("o")
                         .long("output")
                         .value_name("FOLDER")
-                        .help("Set the output folder")
-                        .required(true),
+                        .help("Set the output folder, defaults to the pack name in the current folder"),
                 )
                 .arg(
                     Arg::with_name("filter")
@@ -211,6 +337,255 @@ fn main() {
                         )
                         .number_of_values(1)
                         .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("invert-filter")
+                        .long("invert-filter")
+                        .help("Extract the files not matching any filter instead")
+                        .requires("filter"),
+                )
+                .arg(
+                    Arg::with_name("names-from")
+                        .long("names-from")
+                        .value_name("LIST_FILE")
+                        .help("Only extract the files with the names listed in LIST_FILE, one per line, - for stdin")
+                        .conflicts_with("filter"),
+                )
+                .arg(
+                    Arg::with_name("null")
+                        .short("0")
+                        .long("null")
+                        .help("Names in the --names-from list are separated by NUL characters, like list --print0 outputs")
+                        .requires("names-from"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .help("Only extract the first N (matching) files"),
+                )
+                .arg(
+                    Arg::with_name("resume")
+                        .long("resume")
+                        .value_name("STATE_FILE")
+                        .help("Record the files written to STATE_FILE, and skip those it already lists to resume an interrupted extraction"),
+                )
+                .arg(
+                    Arg::with_name("retries")
+                        .long("retries")
+                        .value_name("N")
+                        .help("Retry writing a file up to N times on transient io errors")
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("sequence")
+                        .long("sequence")
+                        .help("Write files as 00001.bin, 00002.bin... in index order, with their names listed in sequence.txt"),
+                )
+                .arg(
+                    Arg::with_name("order-list")
+                        .long("order-list")
+                        .help("Write the versions and names of the files in index order to order.txt, for pack --from-list")
+                        .conflicts_with("sequence"),
+                )
+                .arg(
+                    Arg::with_name("hash-skip")
+                        .long("hash-skip")
+                        .help("Skip writing files which already exist with the same content"),
+                )
+                .arg(
+                    Arg::with_name("sparse")
+                        .long("sparse")
+                        .help("Leave the blocks of zeros of the files as holes, on file systems supporting sparse files"),
+                )
+                .arg(
+                    Arg::with_name("restore-empty-dirs")
+                        .long("restore-empty-dirs")
+                        .help("Recreate the empty folders recorded with pack --record-empty-dirs"),
+                )
+                .arg(
+                    Arg::with_name("latest")
+                        .long("latest")
+                        .help("Only extract the highest version of files present several times"),
+                )
+                .arg(
+                    Arg::with_name("max-entry-size")
+                        .long("max-entry-size")
+                        .value_name("BYTES")
+                        .help("Fail instead of decoding a file larger than BYTES, for untrusted packs"),
+                )
+                .arg(
+                    Arg::with_name("max-ratio")
+                        .long("max-ratio")
+                        .value_name("N")
+                        .help("Fail instead of decoding a file expanding more than N times its stored size")
+                        .default_value("1032"),
+                )
+                .arg(
+                    Arg::with_name("deflate-fallback")
+                        .long("deflate-fallback")
+                        .help("Decode files without a zlib header as raw deflate"),
+                )
+                .arg(
+                    Arg::with_name("no-space-check")
+                        .long("no-space-check")
+                        .help("Don't check that the output volume has room for the files before extracting"),
+                )
+                .arg(
+                    Arg::with_name("link-dupes")
+                        .long("link-dupes")
+                        .help("Extract files sharing their content with an extracted file as symbolic links to it"),
+                )
+                .arg(
+                    Arg::with_name("symlink-policy")
+                        .long("symlink-policy")
+                        .value_name("POLICY")
+                        .help("With --link-dupes, create symbolic links (default), hard links, or copies")
+                        .possible_values(&["symlink", "hardlink", "copy"]),
+                )
+                .arg(
+                    Arg::with_name("progress-json")
+                        .long("progress-json")
+                        .help("Report the progress on stderr as JSON lines, e.g. for a GUI"),
+                )
+                .arg(
+                    Arg::with_name("max-throughput")
+                        .long("max-throughput")
+                        .value_name("MB")
+                        .help("Write at most MB megabytes per second on average, e.g. 20 or 0.5"),
+                )
+                .arg(
+                    Arg::with_name("mode")
+                        .long("mode")
+                        .value_name("MODE")
+                        .help("Give the extracted files these permissions in octal, e.g. 0644 (unix only)"),
+                )
+                .arg(
+                    Arg::with_name("dir-mode")
+                        .long("dir-mode")
+                        .value_name("MODE")
+                        .help("Give the created folders these permissions in octal, e.g. 0755 (unix only)"),
+                )
+                .arg(
+                    Arg::with_name("min-free-space")
+                        .long("min-free-space")
+                        .value_name("MB")
+                        .help("Stop once the output volume has less than MB megabytes free, checked every 64 files"),
+                )
+                .arg(
+                    Arg::with_name("case-conflict")
+                        .long("case-conflict")
+                        .value_name("POLICY")
+                        .help("Warn about, rename or fail on files whose names differ only in case")
+                        .possible_values(&["warn", "rename", "error"]),
+                )
+                .arg(
+                    Arg::with_name("on-duplicate")
+                        .long("on-duplicate")
+                        .value_name("POLICY")
+                        .help("Extract only the latest, all or none of the files present several times in the pack")
+                        .possible_values(&["keep-latest", "keep-all", "error"]),
+                )
+                .arg(
+                    Arg::with_name("order-by")
+                        .long("order-by")
+                        .value_name("ORDER")
+                        .help("Write the files by size or by name instead of in index order")
+                        .possible_values(&["size", "name"]),
+                )
+                .arg(
+                    Arg::with_name("report-unmatched-filters")
+                        .long("report-unmatched-filters")
+                        .help("Warn about the filters which match no file of the pack"),
+                )
+                .arg(
+                    Arg::with_name("error-on-unmatched")
+                        .long("error-on-unmatched")
+                        .help("Also fail when a filter matches no file of the pack"),
+                )
+                .arg(
+                    Arg::with_name("blob")
+                        .long("blob")
+                        .value_name("FILE")
+                        .help("Write the content of all the files one after the other into FILE instead of a folder")
+                        .requires("manifest")
+                        .conflicts_with_all(&["output", "sequence", "order-list", "link-dupes", "resume"]),
+                )
+                .arg(
+                    Arg::with_name("manifest")
+                        .long("manifest")
+                        .value_name("FILE")
+                        .help("Write the names, offsets and lengths of the files in the blob to FILE as JSON")
+                        .requires("blob"),
+                )
+                .arg(
+                    Arg::with_name("unix-paths")
+                        .long("unix-paths")
+                        .help("Write the names of the manifest with / instead of \\")
+                        .requires("manifest"),
+                )
+                .arg(
+                    Arg::with_name("header-size")
+                        .long("header-size")
+                        .value_name("BYTES")
+                        .help("Read the index after a header of BYTES bytes instead of 0x220, for variant packs"),
+                )
+                .arg(
+                    Arg::with_name("max-entries")
+                        .long("max-entries")
+                        .value_name("N")
+                        .help("Refuse a pack claiming more than N files, 10000000 by default"),
+                )
+                .arg(
+                    Arg::with_name("text-eol")
+                        .long("text-eol")
+                        .value_name("EOL")
+                        .help("Convert the line endings of the text files matching --text-glob when extracting")
+                        .possible_values(&["lf", "crlf"])
+                        .requires("text-glob"),
+                )
+                .arg(
+                    Arg::with_name("text-glob")
+                        .long("text-glob")
+                        .value_name("GLOB")
+                        .help("Set the text files for --text-eol, e.g. *.xml, multiple occurrences mean OR")
+                        .number_of_values(1)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("key-generator")
+                        .long("key-generator")
+                        .value_name("GENERATOR")
+                        .help("Set the generator of the key stream encrypting the content, mt19937-64 for some other clients")
+                        .possible_values(&["mt19937", "mt19937-64"])
+                        .default_value("mt19937"),
+                )
+                .arg(
+                    Arg::with_name("seed-from")
+                        .long("seed-from")
+                        .value_name("SOURCE")
+                        .help("Experimental: also seed the key stream of every file from its name or its offset, like packs of some other tools; version by default, like the game")
+                        .possible_values(&["version", "name", "offset"]),
+                )
+                .arg(
+                    Arg::with_name("encoding")
+                        .long("encoding")
+                        .value_name("ENCODING")
+                        .help("Set the encoding of file names in the pack, cp949 or sjis for some old korean or japanese packs")
+                        .possible_values(&["utf8", "cp949", "sjis"])
+                        .default_value("utf8"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Print a summary of a pack")
+                .arg(
+                    Arg::with_name("input")
+                        .short("i")
+                        .long("input")
+                        .value_name("PACK_NAME")
+                        .help("Set the input pack name")
+                        .required(true),
                 ),
         )
         .subcommand(
@@ -222,7 +597,45 @@ fn main() {
                         .long("input")
                         .value_name("PACK_NAME")
                         .help("Set the input pack name to list")
-                        .required(true),
+                        .required_unless("dir"),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .long("dir")
+                        .value_name("FOLDER")
+                        .help("List every .pack in a folder, prefixing the files with their pack name")
+                        .conflicts_with("input"),
+                )
+                .arg(
+                    Arg::with_name("merge")
+                        .long("merge")
+                        .help("With --dir, only keep the highest version of files found in several packs")
+                        .requires("dir"),
+                )
+                .arg(
+                    Arg::with_name("latest")
+                        .long("latest")
+                        .help("Only output the highest version of files present several times, use --merge with --dir")
+                        .conflicts_with("dir"),
+                )
+                .arg(
+                    Arg::with_name("detect-type")
+                        .long("detect-type")
+                        .help("Print the type of every file (dds, png, xml...) guessed from its first bytes")
+                        .conflicts_with("dir"),
+                )
+                .arg(
+                    Arg::with_name("raw-entry")
+                        .long("raw-entry")
+                        .value_name("NAME")
+                        .help("Print the raw index entry of the file NAME as hex, instead of the list")
+                        .conflicts_with("dir"),
+                )
+                .arg(
+                    Arg::with_name("layout")
+                        .long("layout")
+                        .help("Print the absolute offset and the content offset in hex, the stored and the uncompressed size of every file")
+                        .conflicts_with("dir"),
                 )
                 .arg(
                     Arg::with_name("output")
@@ -235,37 +648,527 @@ fn main() {
                     Arg::with_name("with-version")
                         .long("with-version")
                         .help("Print the version of every file"),
+                )
+                .arg(
+                    Arg::with_name("filter")
+                        .short("f")
+                        .long("filter")
+                        .value_name("FILTER(S)")
+                        .help("Only output the files matching a filter, in regexp, multiple occurrences mean OR")
+                        .number_of_values(1)
+                        .multiple(true),
+                )
+                .arg(
+                    Arg::with_name("invert-filter")
+                        .long("invert-filter")
+                        .help("Output the files not matching any filter instead")
+                        .requires("filter"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Print the files as text, or as JSON with all their fields and timestamps")
+                        .possible_values(&["text", "json"])
+                        .conflicts_with_all(&["dir", "print0"]),
+                )
+                .arg(
+                    Arg::with_name("unix-paths")
+                        .long("unix-paths")
+                        .help("Print the names with / instead of \\, e.g. for URLs"),
+                )
+                .arg(
+                    Arg::with_name("print0")
+                        .long("print0")
+                        .help("End every line with a NUL character instead of a newline, for extract --names-from - -0"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .help("Only output the first N files"),
+                )
+                .arg(
+                    Arg::with_name("header-size")
+                        .long("header-size")
+                        .value_name("BYTES")
+                        .help("Read the index after a header of BYTES bytes instead of 0x220, for variant packs"),
+                )
+                .arg(
+                    Arg::with_name("max-entries")
+                        .long("max-entries")
+                        .value_name("N")
+                        .help("Refuse a pack claiming more than N files, 10000000 by default"),
+                )
+                .arg(
+                    Arg::with_name("key-generator")
+                        .long("key-generator")
+                        .value_name("GENERATOR")
+                        .help("Set the generator of the key stream encrypting the content, mt19937-64 for some other clients")
+                        .possible_values(&["mt19937", "mt19937-64"])
+                        .default_value("mt19937"),
+                )
+                .arg(
+                    Arg::with_name("seed-from")
+                        .long("seed-from")
+                        .value_name("SOURCE")
+                        .help("Experimental: also seed the key stream of every file from its name or its offset, like packs of some other tools; version by default, like the game")
+                        .possible_values(&["version", "name", "offset"]),
+                )
+                .arg(
+                    Arg::with_name("encoding")
+                        .long("encoding")
+                        .value_name("ENCODING")
+                        .help("Set the encoding of file names in the pack, cp949 or sjis for some old korean or japanese packs")
+                        .possible_values(&["utf8", "cp949", "sjis"])
+                        .default_value("utf8"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check that every file of a pack can be decoded")
+                .arg(
+                    Arg::with_name("input")
+                        .short("i")
+                        .long("input")
+                        .value_name("PACK_NAME")
+                        .help("Set the input pack name to verify")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("layout")
+                        .long("layout")
+                        .help("Only check the content regions for overlaps and gaps, without decoding"),
+                )
+                .arg(
+                    Arg::with_name("fast")
+                        .long("fast")
+                        .help("Only check the hash of the trailer written by pack --with-trailer")
+                        .conflicts_with("layout"),
+                )
+                .arg(
+                    Arg::with_name("headers-only")
+                        .long("headers-only")
+                        .alias("entry-count-only")
+                        .help("Only check the file counts of the header and the length of the file, without reading the index")
+                        .conflicts_with_all(&["layout", "fast"]),
+                )
+                .arg(
+                    Arg::with_name("compat")
+                        .long("compat")
+                        .value_name("PROFILE")
+                        .help("Also check that the pack only uses what the client of PROFILE is known to load")
+                        .possible_values(&["current", "old-korea", "old-japan"])
+                        .conflicts_with_all(&["fast", "headers-only"]),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Print the problems as text, or the result of every file and a summary as JSON, e.g. for CI")
+                        .possible_values(&["text", "json"])
+                        .conflicts_with_all(&["layout", "fast", "headers-only"]),
+                )
+                .arg(
+                    Arg::with_name("header-size")
+                        .long("header-size")
+                        .value_name("BYTES")
+                        .help("Read the index after a header of BYTES bytes instead of 0x220, for variant packs"),
+                )
+                .arg(
+                    Arg::with_name("max-entries")
+                        .long("max-entries")
+                        .value_name("N")
+                        .help("Refuse a pack claiming more than N files, 10000000 by default"),
+                )
+                .arg(
+                    Arg::with_name("key-generator")
+                        .long("key-generator")
+                        .value_name("GENERATOR")
+                        .help("Set the generator of the key stream encrypting the content, mt19937-64 for some other clients")
+                        .possible_values(&["mt19937", "mt19937-64"]),
+                )
+                .arg(
+                    Arg::with_name("seed-from")
+                        .long("seed-from")
+                        .value_name("SOURCE")
+                        .help("Experimental: also seed the key stream of every file from its name or its offset, like packs of some other tools; version by default, like the game")
+                        .possible_values(&["version", "name", "offset"]),
+                )
+                .arg(
+                    Arg::with_name("encoding")
+                        .long("encoding")
+                        .value_name("ENCODING")
+                        .help("Set the encoding of file names in the pack, utf8 by default, cp949 or sjis for some old korean or japanese packs")
+                        .possible_values(&["utf8", "cp949", "sjis"])
+                        .conflicts_with("compat"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("repair")
+                .about("Rebuild the header of a damaged pack from its index")
+                .arg(
+                    Arg::with_name("input")
+                        .short("i")
+                        .long("input")
+                        .value_name("PACK_NAME")
+                        .help("Set the input pack name to repair")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("PACK_NAME")
+                        .help("Write the repaired pack to another file instead of fixing it in place"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("describe-format")
+                .about("Print the layout of the pack format as JSON")
+                .setting(AppSettings::Hidden),
+        )
+        .subcommand(
+            SubCommand::with_name("keystream")
+                .about("Print the start of the key stream of a version")
+                .setting(AppSettings::Hidden)
+                .arg(
+                    Arg::with_name("version")
+                        .short("k")
+                        .long("version")
+                        .value_name("VERSION")
+                        .help("Set the version seeding the key stream")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("len")
+                        .long("len")
+                        .value_name("BYTES")
+                        .help("Set how many bytes to print")
+                        .default_value("256"),
+                )
+                .arg(
+                    Arg::with_name("key-generator")
+                        .long("key-generator")
+                        .value_name("GENERATOR")
+                        .help("Set the generator of the key stream, mt19937-64 for some other clients")
+                        .possible_values(&["mt19937", "mt19937-64"])
+                        .default_value("mt19937"),
+                )
+                .arg(
+                    Arg::with_name("raw")
+                        .long("raw")
+                        .help("Write the bytes themselves instead of hex"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Compare the files of two packs")
+                .arg(
+                    Arg::with_name("old")
+                        .value_name("OLD_PACK")
+                        .help("The old pack")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .value_name("NEW_PACK")
+                        .help("The new pack")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("compare-content")
+                        .long("compare-content")
+                        .help("Decode the files in both packs and compare their content, not only their size"),
+                )
+                .arg(
+                    Arg::with_name("header-size")
+                        .long("header-size")
+                        .value_name("BYTES")
+                        .help("Read the index after a header of BYTES bytes instead of 0x220, for variant packs"),
+                )
+                .arg(
+                    Arg::with_name("max-entries")
+                        .long("max-entries")
+                        .value_name("N")
+                        .help("Refuse a pack claiming more than N files, 10000000 by default"),
+                )
+                .arg(
+                    Arg::with_name("key-generator")
+                        .long("key-generator")
+                        .value_name("GENERATOR")
+                        .help("Set the generator of the key stream encrypting the content, mt19937-64 for some other clients")
+                        .possible_values(&["mt19937", "mt19937-64"]),
+                )
+                .arg(
+                    Arg::with_name("seed-from")
+                        .long("seed-from")
+                        .value_name("SOURCE")
+                        .help("Experimental: also seed the key stream of every file from its name or its offset, like packs of some other tools; version by default, like the game")
+                        .possible_values(&["version", "name", "offset"]),
+                )
+                .arg(
+                    Arg::with_name("encoding")
+                        .long("encoding")
+                        .value_name("ENCODING")
+                        .help("Set the encoding of file names in the packs, utf8 by default, cp949 or sjis for some old korean or japanese packs")
+                        .possible_values(&["utf8", "cp949", "sjis"]),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("which")
+                .about("Find which packs of a folder contain a file")
+                .arg(
+                    Arg::with_name("query")
+                        .value_name("QUERY")
+                        .help("The file to look for, in regexp")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .long("dir")
+                        .value_name("FOLDER")
+                        .help("Set the folder containing the packs")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("glob")
+                        .long("glob")
+                        .help("Match the query as a glob pattern (e.g. data/*.xml) instead of a regexp"),
                 ),
         )
         .get_matches();
+    mabi_pack::log::set_quiet(args.is_present("quiet"));
+    mabi_pack::log::set_verbose(args.is_present("verbose"));
 
     let ret = match if let Some(matches) = args.subcommand_matches("list") {
-        list::run_list(
-            matches.value_of("input").unwrap(),
-            matches.value_of("output"),
-            matches.is_present("with-version"),
-        )
+        parse_arg(matches, "limit").and_then(|limit| {
+            let options = list::ListOptions {
+                with_version: matches.is_present("with-version"),
+                limit,
+                merge: matches.is_present("merge"),
+                latest: matches.is_present("latest"),
+                detect_type: matches.is_present("detect-type"),
+                layout: matches.is_present("layout"),
+                read_options: read_options(matches)?,
+                filters: matches
+                    .values_of("filter")
+                    .map_or(vec![], |v| v.map(|s| s.to_string()).collect()),
+                invert_filter: matches.is_present("invert-filter"),
+                print0: matches.is_present("print0"),
+                format: parse_arg(matches, "format")?.unwrap_or_default(),
+                unix_paths: matches.is_present("unix-paths"),
+            };
+            if let Some(name) = matches.value_of("raw-entry") {
+                return list::run_dump_entry(
+                    matches.value_of("input").unwrap(),
+                    name,
+                    matches.value_of("output"),
+                    &options.read_options,
+                );
+            }
+            match matches.value_of("dir") {
+                Some(dir) => list::run_list_dir(dir, matches.value_of("output"), &options),
+                None => list::run_list(
+                    matches.value_of("input").unwrap(),
+                    matches.value_of("output"),
+                    &options,
+                ),
+            }
+        })
     } else if let Some(matches) = args.subcommand_matches("extract") {
-        extract::run_extract(
-            matches.value_of("input").unwrap(),
-            matches.value_of("output").unwrap(),
-            matches
-                .values_of("filter")
-                .map(|e| e.collect())
-                .unwrap_or(vec![]),
-        )
+        let input = matches.value_of("input").unwrap();
+        matches
+            .value_of("output")
+            .map_or_else(
+                // a blob is written without any folder
+                || match matches.is_present("blob") {
+                    true => Ok(String::new()),
+                    false => extract::default_output_folder(
+                        input,
+                        matches.is_present("resume") || matches.is_present("hash-skip"),
+                    ),
+                },
+                |o| Ok(o.to_string()),
+            )
+            .and_then(|output| {
+                let filters = matches
+                    .values_of("filter")
+                    .map(|e| e.collect())
+                    .unwrap_or(vec![]);
+                let options = extract::ExtractOptions {
+                    limit: parse_arg(matches, "limit")?,
+                    retries: parse_arg(matches, "retries")?.unwrap_or(0),
+                    sequence: matches.is_present("sequence"),
+                    order_list: matches.is_present("order-list"),
+                    skip_unchanged: matches.is_present("hash-skip"),
+                    sparse: matches.is_present("sparse"),
+                    unix_paths: matches.is_present("unix-paths"),
+                    restore_empty_dirs: matches.is_present("restore-empty-dirs"),
+                    read_options: read_options(matches)?,
+                    text_eol: text_eol(matches)?,
+                    latest: matches.is_present("latest"),
+                    max_entry_size: parse_arg(matches, "max-entry-size")?,
+                    max_ratio: parse_arg(matches, "max-ratio")?,
+                    deflate_fallback: matches.is_present("deflate-fallback"),
+                    skip_space_check: matches.is_present("no-space-check"),
+                    link_dupes: matches.is_present("link-dupes"),
+                    link_policy: parse_arg(matches, "symlink-policy")?.unwrap_or_default(),
+                    invert_filter: matches.is_present("invert-filter"),
+                    resume: matches.value_of("resume").map(|s| s.to_string()),
+                    names: matches
+                        .value_of("names-from")
+                        .map(|list| extract::read_name_list(list, matches.is_present("null")))
+                        .transpose()?,
+                    cancel: None,
+                    case_conflict: parse_arg(matches, "case-conflict")?,
+                    min_free_space: parse_arg::<u64>(matches, "min-free-space")?
+                        .map(|mb| mb.saturating_mul(1_000_000)),
+                    progress_json: matches.is_present("progress-json"),
+                    file_mode: parse_mode(matches, "mode")?,
+                    dir_mode: parse_mode(matches, "dir-mode")?,
+                    max_throughput: match parse_arg::<f64>(matches, "max-throughput")? {
+                        Some(mb) if mb.is_nan() || mb <= 0.0 => {
+                            return Err(MabiError::InvalidArgument(format!(
+                                "max-throughput: {}",
+                                mb
+                            )))
+                        }
+                        mb => mb.map(|mb| (mb * 1_000_000.0) as u64),
+                    },
+                    on_duplicate: parse_arg(matches, "on-duplicate")?,
+                    report_unmatched_filters: matches.is_present("report-unmatched-filters"),
+                    error_on_unmatched: matches.is_present("error-on-unmatched"),
+                    order_by: parse_arg(matches, "order-by")?,
+                };
+                match matches.value_of("blob") {
+                    Some(blob) => extract::run_extract_blob(
+                        input,
+                        blob,
+                        matches.value_of("manifest").unwrap(),
+                        filters,
+                        &options,
+                    ),
+                    None => extract::run_extract(input, &output, filters, &options),
+                }
+            })
     } else if let Some(matches) = args.subcommand_matches("pack") {
-        pack::run_pack(
+        // an interrupted pack cleans up its output instead of dying mid-write
+        ctrlc::set_handler(mabi_pack::interrupt).expect("failed to set the Ctrl-C handler");
+        parse_arg(matches, "compression").and_then(|compression| {
+            let times_fallback = parse_arg(matches, "times-fallback")?;
+            let options = pack::PackOptions {
+                dedupe: matches.is_present("dedupe"),
+                root_name: matches.value_of("root-name").map(|s| s.to_string()),
+                header_size: parse_size(matches, "header-size")?,
+                compression: compression.unwrap_or_default(),
+                store_incompressible: parse_arg(matches, "store-incompressible")?,
+                record_empty_dirs: matches.is_present("record-empty-dirs"),
+                skip_hidden: matches.is_present("no-hidden"),
+                skip_links: matches.is_present("no-follow-junctions"),
+                skip_empty: matches.is_present("skip-empty"),
+                warn_empty: matches.is_present("warn-empty"),
+                sync: matches.is_present("sync"),
+                temp_dir: matches.value_of("temp-dir").map(|s| s.to_string()),
+                with_trailer: matches.is_present("with-trailer"),
+                pad_to: parse_arg(matches, "pad-to")?,
+                from_list: matches.value_of("from-list").map(|s| s.to_string()),
+                on_missing: parse_arg(matches, "on-missing")?.unwrap_or_default(),
+                continue_on_error: matches.is_present("continue-on-error"),
+                validate_names: matches.is_present("validate-names"),
+                times_fallback: times_fallback.unwrap_or_default(),
+                encoding: parse_arg(matches, "encoding")?.unwrap_or_default(),
+                key_generator: parse_arg(matches, "key-generator")?.unwrap_or_default(),
+                seed_derivation: parse_arg(matches, "seed-from")?.unwrap_or_default(),
+                text_eol: text_eol(matches)?,
+                report_json: matches.value_of("report-json").map(|s| s.to_string()),
+                verify: if matches.is_present("verify-full") {
+                    Some(pack::VerifyScope::All)
+                } else if matches.is_present("verify") {
+                    Some(pack::VerifyScope::Sample)
+                } else {
+                    None
+                },
+                cancel: None,
+                progress_json: matches.is_present("progress-json"),
+            };
+            let output = matches.value_of("output").unwrap();
+            let version = matches.value_of("verkey").unwrap();
+            match matches.value_of("from-stream") {
+                Some(stream) => pack::run_pack_from_stream(stream, output, version, &options),
+                None => pack::run_pack(
+                    matches.value_of("input").unwrap(),
+                    output,
+                    version,
+                    &options,
+                ),
+            }
+        })
+    } else if let Some(matches) = args.subcommand_matches("info") {
+        info::run_info(matches.value_of("input").unwrap())
+    } else if let Some(matches) = args.subcommand_matches("verify") {
+        parse_arg(matches, "compat").and_then(|compat| {
+            let options = verify::VerifyOptions {
+                layout: matches.is_present("layout"),
+                fast: matches.is_present("fast"),
+                headers_only: matches.is_present("headers-only"),
+                compat,
+                format: parse_arg(matches, "format")?.unwrap_or_default(),
+                read_options: read_options(matches)?,
+            };
+            verify::run_verify(matches.value_of("input").unwrap(), &options)
+        })
+    } else if let Some(matches) = args.subcommand_matches("repair") {
+        repair::run_repair(
             matches.value_of("input").unwrap(),
-            matches.value_of("output").unwrap(),
-            matches.value_of("verkey").unwrap(),
+            matches.value_of("output"),
+        )
+    } else if args.subcommand_matches("describe-format").is_some() {
+        describe::run_describe_format()
+    } else if let Some(matches) = args.subcommand_matches("keystream") {
+        parse_size(matches, "len").and_then(|len| {
+            describe::run_dump_keystream(
+                matches.value_of("version").unwrap(),
+                len.unwrap(),
+                parse_arg(matches, "key-generator")?.unwrap_or_default(),
+                matches.is_present("raw"),
+            )
+        })
+    } else if let Some(matches) = args.subcommand_matches("diff") {
+        read_options(matches).and_then(|read_options| {
+            diff::run_diff(
+                matches.value_of("old").unwrap(),
+                matches.value_of("new").unwrap(),
+                &diff::DiffOptions {
+                    compare_content: matches.is_present("compare-content"),
+                    read_options,
+                },
+            )
+        })
+    } else if let Some(matches) = args.subcommand_matches("which") {
+        which::run_which(
+            matches.value_of("query").unwrap(),
+            matches.value_of("dir").unwrap(),
+            &which::WhichOptions {
+                glob: matches.is_present("glob"),
+            },
         )
     } else {
-        println!("please select a subcommand (type --help to get details)");
+        eprintln!("please select a subcommand (type --help to get details)");
         Ok(())
     } {
+        // the reader of the output went away, e.g. `mabi-pack list ... | head`
+        Err(MabiError::IoFail(e)) if e.kind() == io::ErrorKind::BrokenPipe => 0,
         Err(e) => {
-            println!("Err: {:?}", e);
+            if args.is_present("json-errors") {
+                let file = args
+                    .subcommand()
+                    .1
+                    .and_then(|m| m.value_of("input").or(m.value_of("dir")));
+                eprintln!("{}", e.to_json(file));
+            } else {
+                eprintln!("Err: {:?}", e);
+            }
             1
         }
         _ => 0,
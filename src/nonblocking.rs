@@ -0,0 +1,77 @@
+use super::archive::{Pack, PackIndex};
+use super::codec::decode_entry_seeded;
+use super::extract::{self, ExtractOptions};
+use super::pack::{self, PackOptions};
+use super::{MabiError, ReadOptions};
+use std::io::SeekFrom;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::task;
+
+// Runs blocking work on the blocking thread pool of the runtime.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, MabiError> + Send + 'static,
+) -> Result<T, MabiError> {
+    task::spawn_blocking(f)
+        .await
+        .map_err(|e| MabiError::IoFail(std::io::Error::other(e)))?
+}
+
+/// `pack::run_pack` on the blocking thread pool, not to stall the worker
+/// threads of the runtime.
+pub async fn run_pack(
+    input_folder: String,
+    output_fname: String,
+    version: String,
+    options: PackOptions,
+) -> Result<(), MabiError> {
+    blocking(move || pack::run_pack(&input_folder, &output_fname, &version, &options)).await
+}
+
+/// `extract::run_extract` on the blocking thread pool, not to stall the worker
+/// threads of the runtime.
+pub async fn run_extract(
+    fname: String,
+    output_folder: String,
+    filters: Vec<String>,
+    options: ExtractOptions,
+) -> Result<(), MabiError> {
+    blocking(move || {
+        let filters = filters.iter().map(String::as_str).collect();
+        extract::run_extract(&fname, &output_folder, filters, &options)
+    })
+    .await
+}
+
+/// `Pack::open_index_only_with` on the blocking thread pool.
+pub async fn open_index_only(path: String, options: ReadOptions) -> Result<PackIndex, MabiError> {
+    blocking(move || Pack::open_index_only_with(&path, &options)).await
+}
+
+/// Reads a file of a pack opened with `open_index_only`, like `Pack::read`:
+/// its content is read with `tokio::fs`, then decoded on the blocking thread
+/// pool.
+pub async fn read(
+    path: &str,
+    index: &PackIndex,
+    name: &str,
+    options: &ReadOptions,
+) -> Result<Vec<u8>, MabiError> {
+    let fi = index
+        .find(name)
+        .ok_or_else(|| MabiError::NotFound(name.to_string()))?;
+    let head_info = index.head_info();
+    let mut fs = File::open(path).await?;
+    fs.seek(SeekFrom::Start(
+        head_info.header_size() + head_info.index_size() as u64 + fi.off() as u64,
+    ))
+    .await?;
+    let mut raw = vec![0; fi.raw_size() as usize];
+    fs.read_exact(&mut raw).await?;
+    let seed = options
+        .seed_derivation
+        .seed(fi.version(), fi.name(), fi.off());
+    let (len, compression) = (fi.uncompr_size(), fi.compression());
+    let key_generator = options.key_generator;
+    blocking(move || decode_entry_seeded(&raw, seed, Some(len), compression, key_generator)).await
+}
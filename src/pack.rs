@@ -1,32 +1,158 @@
-use super::{FileInfo, HeadInfo, MabiError, HEADER_SIZE};
+use super::codec::encode_entry_seeded;
+use super::eol::TextEol;
+use super::progress::Progress;
+use super::report::write_report;
+use super::trailer::{to_hex, write_trailer_with_hash, HashWriter, TRAILER_SIZE};
+use super::verify::verify_built_pack;
+use super::{
+    check_header_size, check_interrupted, parse_version, Compression, FileInfo, HeadInfo,
+    KeyGenerator, MabiError, NameEncoding, ReadOptions, SeedDerivation, HEADER_SIZE,
+    MIN_HEADER_SIZE,
+};
 use byteorder::{LittleEndian, WriteBytesExt};
-use libflate::zlib;
-use mersenne_twister::MT19937;
-use rand::{Rng, SeedableRng};
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::{HashMap, HashSet};
 use std::fs::{metadata, File, OpenOptions};
-use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::{Path, MAIN_SEPARATOR};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR, MAIN_SEPARATOR_STR};
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
-use walkdir::WalkDir;
+use walkdir::{DirEntry, WalkDir};
 
-fn pack_file(root_dir: &str, rel_path: &str, key: u32) -> Result<(FileInfo, Vec<u8>), MabiError> {
+#[derive(Default)]
+pub struct PackOptions {
+    /// Store byte-identical files only once. The index entries of the duplicates
+    /// point at the same content region (`off`/`raw_size`), so content regions are
+    /// no longer guaranteed to be one per entry.
+    pub dedupe: bool,
+    /// The root path embedded in the header, `data\` if not set.
+    pub root_name: Option<String>,
+    /// The size of the header, padded with zeros after its fields, for variant
+    /// packs (see `ReadOptions::header_size`). `HEADER_SIZE` if not set.
+    pub header_size: Option<u64>,
+    pub compression: Compression,
+    /// Store a file uncompressed when compressing it saves less than this
+    /// percentage of its size, e.g. for already compressed assets.
+    pub store_incompressible: Option<u8>,
+    /// Store empty folders as zero-length entries whose name ends with `\`, as
+    /// the format has no folder entries.
+    pub record_empty_dirs: bool,
+    /// Leave out the files and folders whose name starts with `.`, or which
+    /// are hidden on Windows.
+    pub skip_hidden: bool,
+    /// Leave out the symbolic links and the junctions of the input folder,
+    /// which are followed otherwise, each folder being packed only once.
+    pub skip_links: bool,
+    /// Leave out the files of 0 bytes, e.g. placeholders left by a build. They
+    /// aren't counted in the header either.
+    pub skip_empty: bool,
+    /// Print the files of 0 bytes, skipped or not.
+    pub warn_empty: bool,
+    /// Make sure the pack is on the disk before returning.
+    pub sync: bool,
+    /// Where to write the pack while it's built, before renaming it to the
+    /// output, instead of next to the output. On another volume, the pack is
+    /// copied instead, so the output may hold an incomplete pack for a while.
+    /// Also where the content goes until the header and the index are known,
+    /// instead of next to the output, or the temporary folder of the system
+    /// when packing to stdout.
+    pub temp_dir: Option<String>,
+    /// Append a trailer with the hash of the whole pack, see `TRAILER_SIZE`.
+    pub with_trailer: bool,
+    /// Pad the pack with zeros to exactly this many bytes, trailer included.
+    /// The header still only counts the actual content.
+    pub pad_to: Option<u64>,
+    /// Pack the files of this list instead of the whole input folder, see
+    /// `read_file_list`.
+    pub from_list: Option<String>,
+    /// What to do with files of the list which don't exist.
+    pub on_missing: OnMissing,
+    /// Check all the names before packing anything, failing with
+    /// `InvalidName` on the first one the game may not load, see
+    /// `validate_names`.
+    pub validate_names: bool,
+    /// Leave out the files which can't be read, e.g. locked by a running
+    /// client, instead of failing. They are listed once the pack is written,
+    /// and aren't counted in the header.
+    pub continue_on_error: bool,
+    pub times_fallback: TimesFallback,
+    /// The encoding of the names in the index, for packs of the korean or
+    /// japanese clients.
+    pub encoding: NameEncoding,
+    /// The generator of the key stream encrypting the content, MT19937 like the
+    /// game by default.
+    pub key_generator: KeyGenerator,
+    /// Where the seed of the key stream comes from, the version like the game
+    /// by default. Experimental; `Name` can't be combined with `dedupe`.
+    pub seed_derivation: SeedDerivation,
+    /// Convert the line endings of the matching text files before packing them.
+    pub text_eol: Option<TextEol>,
+    /// Write a JSON report of the built pack to this file, see `write_report`.
+    pub report_json: Option<String>,
+    /// Read the pack back once written, and check that its files decode to
+    /// their source. The pack is kept if it fails.
+    pub verify: Option<VerifyScope>,
+    /// Set to stop packing, e.g. from a cancel button: `run_pack` then fails
+    /// with `Cancelled` before the next file, and removes its incomplete output.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Report the progress on stderr as JSON lines, see `Progress`.
+    pub progress_json: bool,
+}
+
+pub const DEFAULT_ROOT_NAME: &str = "data\\";
+/// The longest name `validate_names` accepts, in bytes once encoded. The format
+/// has no limit, but the client is a Windows program, whose paths are limited
+/// to 260 characters; this leaves room for the folder of the game and the root
+/// name.
+pub const MAX_NAME_SIZE: usize = 200;
+/// The output name writing the pack to stdout, e.g. to pipe it.
+pub const STDOUT_NAME: &str = "-";
+pub(crate) const ROOT_NAME_SIZE: usize = 0x1e0;
+
+// Reads a file to pack, converting its line endings if it matches `text_eol`.
+fn read_file(
+    root_dir: &str,
+    rel_path: &str,
+    text_eol: &Option<TextEol>,
+) -> Result<Vec<u8>, MabiError> {
     let mut stm = vec![];
-    {
-        let mut fs = File::open(Path::new(root_dir).join(rel_path))?;
-        fs.read_to_end(&mut stm)?;
+    let mut fs = File::open(Path::new(root_dir).join(rel_path))?;
+    fs.read_to_end(&mut stm)?;
+    Ok(match text_eol {
+        Some(text_eol) => text_eol.apply(rel_path, stm),
+        None => stm,
+    })
+}
+
+/// Compresses a file and encrypts it with the key stream seeded with `seed`.
+/// With `min_saving`, a file whose compressed size isn't at least
+/// `min_saving`% smaller is stored instead.
+fn pack_file(
+    rel_path: &str,
+    stm: &[u8],
+    key: u32,
+    mut compression: Compression,
+    min_saving: Option<u8>,
+    key_generator: KeyGenerator,
+    seed: u32,
+) -> Result<(FileInfo, Vec<u8>), MabiError> {
+    if stm.len() as u64 > u32::MAX as u64 {
+        return Err(MabiError::PackTooLarge(stm.len() as u64));
+    }
+    let mut encoded_buff = encode_entry_seeded(stm, seed, compression, key_generator)?;
+    if let Some(min_saving) = min_saving {
+        let max_size = stm.len() as u64 * (100 - min_saving.min(100) as u64) / 100;
+        if compression != Compression::Stored && encoded_buff.len() as u64 > max_size {
+            compression = Compression::Stored;
+            encoded_buff = encode_entry_seeded(stm, seed, compression, key_generator)?;
+        }
     }
-    let mut encoder = zlib::Encoder::new(vec![]).unwrap();
-    encoder
-        .write_all(&stm)
-        .map_err(|e| MabiError::CompressError(e.to_string()))?;
-    let mut encoded_buff = encoder
-        .finish()
-        .into_result()
-        .map_err(|e| MabiError::CompressError(e.to_string()))?;
 
-    let mut rng: MT19937 = SeedableRng::from_seed((key << 7) ^ 0xA9C36DE1);
-    for i in 0..encoded_buff.len() {
-        encoded_buff[i] ^= rng.next_u32() as u8;
+    if encoded_buff.len() as u64 > u32::MAX as u64 {
+        return Err(MabiError::PackTooLarge(encoded_buff.len() as u64));
     }
 
     Ok((
@@ -36,12 +162,72 @@ fn pack_file(root_dir: &str, rel_path: &str, key: u32) -> Result<(FileInfo, Vec<
             off: 0,
             raw_size: encoded_buff.len() as u32,
             uncompr_size: stm.len() as u32,
+            compression,
+            // see `timed_entry`
+            created: 0,
+            accessed: 0,
+            modified: 0,
         },
         encoded_buff,
     ))
 }
 
-fn calc_str_size(l: usize) -> (usize, u8) {
+// (length, hash, key)
+type ContentKey = (usize, u64, u32);
+
+/// Remembers where every distinct content has been stored, so that identical
+/// files can share one content region.
+#[derive(Default)]
+struct ContentDedupe {
+    // content key => (source file, off, raw_size, compression)
+    stored: HashMap<ContentKey, Vec<(String, u32, u32, Compression)>>,
+}
+
+impl ContentDedupe {
+    fn content_key(content: &[u8], key: u32) -> ContentKey {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        (content.len(), hasher.finish(), key)
+    }
+
+    /// Finds a stored region with the same content. Hash hits are confirmed by
+    /// comparing against the source file of the stored region.
+    fn find(
+        &self,
+        root_dir: &str,
+        text_eol: &Option<TextEol>,
+        content: &[u8],
+        key: u32,
+    ) -> Result<Option<(u32, u32, Compression)>, MabiError> {
+        if let Some(candidates) = self.stored.get(&Self::content_key(content, key)) {
+            for (rel_path, off, raw_size, compression) in candidates {
+                if read_file(root_dir, rel_path, text_eol)? == content {
+                    return Ok(Some((*off, *raw_size, *compression)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn insert(&mut self, content: &[u8], key: u32, rel_path: &str, fi: &FileInfo) {
+        self.stored
+            .entry(Self::content_key(content, key))
+            .or_default()
+            .push((rel_path.to_string(), fi.off, fi.raw_size, fi.compression));
+    }
+}
+
+/// The size of the string block of a name of `l` bytes, and its marker byte.
+/// The block is the marker, a u32 length for marker 5, then the name and at
+/// least one NUL; `read_str` reads back the bytes after the marker:
+///
+/// - markers 0 to 3: `(marker + 1) * 16 - 1` bytes, so names of up to 14, 30,
+///   46 and 62 bytes;
+/// - marker 4: 95 bytes, so names of up to 94 bytes;
+/// - marker 5: as many bytes as the u32, the block size minus 5. The block is
+///   the smallest multiple of 16 holding the 5 bytes, the name and its NUL,
+///   i.e. `(l + 6 + 15) / 16 * 16`.
+pub(crate) fn calc_str_size(l: usize) -> (usize, u8) {
     match l {
         0..=14 => (16, 0),
         15..=30 => (32, 1),
@@ -52,6 +238,10 @@ fn calc_str_size(l: usize) -> (usize, u8) {
     }
 }
 
+fn is_empty_dir(path: &Path) -> bool {
+    std::fs::read_dir(path).is_ok_and(|mut d| d.next().is_none())
+}
+
 fn get_rel_path(root_dir: &str, full_path: &str) -> Result<String, MabiError> {
     let full_path = Path::new(full_path);
     let rel_name = full_path
@@ -60,16 +250,44 @@ fn get_rel_path(root_dir: &str, full_path: &str) -> Result<String, MabiError> {
     Ok(rel_name.to_string_lossy().into_owned())
 }
 
-fn write_str_block(stm: &mut impl Write, s: &str) -> Result<u64, MabiError> {
-    let (all_len, lead_byte) = calc_str_size(s.as_bytes().len());
+/// Checks that the names can be encoded, aren't longer than `MAX_NAME_SIZE`
+/// and hold no control character, which would end the name early for a reader
+/// expecting a NUL-terminated string, nor a character invalid in a Windows path.
+fn validate_names(file_names: &[PackItem], encoding: NameEncoding) -> Result<(), MabiError> {
+    for item in file_names {
+        let encoded = encode_name(&item.name, encoding)?;
+        if let Some(problem) = name_problem(&item.name, encoded.len(), MAX_NAME_SIZE) {
+            return Err(MabiError::InvalidName(item.name.clone(), problem));
+        }
+    }
+    Ok(())
+}
+
+// Why the game may not load a name of `size` bytes once encoded, if it may not.
+pub(crate) fn name_problem(name: &str, size: usize, max_size: usize) -> Option<String> {
+    if size > max_size {
+        return Some(format!("{} bytes, longer than {}", size, max_size));
+    }
+    name.chars()
+        .find(|&c| c.is_control() || "<>:\"|?*".contains(c))
+        .map(|c| format!("invalid character {:?}", c))
+}
+
+// The name as stored in the index, with `\` as separator.
+fn encode_name(name: &str, encoding: NameEncoding) -> Result<Vec<u8>, MabiError> {
+    encoding.encode(&name.replace(MAIN_SEPARATOR, "\\"))
+}
+
+fn write_str_block(stm: &mut impl Write, s: &[u8]) -> Result<u64, MabiError> {
+    let (all_len, lead_byte) = calc_str_size(s.len());
     stm.write_u8(lead_byte)?;
     let mut wrote_bytes = 1;
     if lead_byte == 5 {
         stm.write_u32::<LittleEndian>(all_len as u32 - 5)?;
         wrote_bytes += 4;
     }
-    stm.write_all(s.replace(MAIN_SEPARATOR, "\\").as_bytes())?;
-    wrote_bytes += s.as_bytes().len();
+    stm.write_all(s)?;
+    wrote_bytes += s.len();
     for _ in 0..all_len - wrote_bytes {
         stm.write_u8(0)?;
     }
@@ -85,108 +303,1130 @@ fn time_to_filetime(t: SystemTime) -> Result<u64, MabiError> {
     Ok(((t * 10000) + 116444736000000000) as u64)
 }
 
-fn write_file_time(stm: &mut impl Write, root_dir: &str, rel_path: &str) -> Result<(), MabiError> {
+/// Timestamps of an entry, as Windows FILETIME values.
+#[derive(Debug, Clone, Copy)]
+pub struct FileTimes {
+    pub created: u64,
+    pub accessed: u64,
+    pub modified: u64,
+}
+
+impl FileTimes {
+    pub fn now() -> Result<FileTimes, MabiError> {
+        let cur = time_to_filetime(SystemTime::now())?;
+        Ok(FileTimes {
+            created: cur,
+            accessed: cur,
+            modified: cur,
+        })
+    }
+}
+
+// An entry with the timestamps to write for it, also set in its `FileInfo`.
+fn timed_entry(mut fi: FileInfo, times: FileTimes) -> (FileInfo, FileTimes) {
+    fi.created = times.created;
+    fi.accessed = times.accessed;
+    fi.modified = times.modified;
+    (fi, times)
+}
+
+/// What to store when the platform can't tell a timestamp of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimesFallback {
+    /// Use the modified time, or the current time if it's missing too.
+    #[default]
+    Modified,
+    Now,
+    Zero,
+}
+
+impl FromStr for TimesFallback {
+    type Err = MabiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "modified" => Ok(TimesFallback::Modified),
+            "now" => Ok(TimesFallback::Now),
+            "zero" => Ok(TimesFallback::Zero),
+            _ => Err(MabiError::InvalidArgument(format!("times fallback: {}", s))),
+        }
+    }
+}
+
+/// Which files of a pack just built `verify` checks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyScope {
+    /// Some files spread over the index.
+    Sample,
+    All,
+}
+
+/// What to do when a file of the list given with `from_list` doesn't exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnMissing {
+    #[default]
+    Error,
+    /// Leave the file out of the pack, with a warning.
+    Skip,
+}
+
+impl FromStr for OnMissing {
+    type Err = MabiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(OnMissing::Error),
+            "skip" => Ok(OnMissing::Skip),
+            _ => Err(MabiError::InvalidArgument(format!("on missing: {}", s))),
+        }
+    }
+}
+
+fn read_file_times(
+    root_dir: &str,
+    rel_path: &str,
+    fallback: TimesFallback,
+) -> Result<FileTimes, MabiError> {
     let meta = metadata(Path::new(root_dir).join(rel_path))?;
+    let get_time = |t: std::io::Result<SystemTime>| t.ok().and_then(|t| time_to_filetime(t).ok());
+    let now = time_to_filetime(SystemTime::now())?;
+    let m_time = get_time(meta.modified());
+    let substitute = match fallback {
+        TimesFallback::Modified => m_time.unwrap_or(now),
+        TimesFallback::Now => now,
+        TimesFallback::Zero => 0,
+    };
     // As creation time is not supported in WSL, replace it with modified time
     //let c_time = time_to_filetime(meta.created()?)?;
-    let a_time = time_to_filetime(meta.accessed()?)?;
-    let m_time = time_to_filetime(meta.modified()?)?;
-    stm.write_u64::<LittleEndian>(m_time)?;
-    stm.write_u64::<LittleEndian>(m_time)?;
-    stm.write_u64::<LittleEndian>(a_time)?;
-    stm.write_u64::<LittleEndian>(m_time)?;
-    stm.write_u64::<LittleEndian>(m_time)?;
+    let a_time = get_time(meta.accessed()).unwrap_or(substitute);
+    let m_time = m_time.unwrap_or(substitute);
+    Ok(FileTimes {
+        created: m_time,
+        accessed: a_time,
+        modified: m_time,
+    })
+}
+
+fn write_file_time(stm: &mut impl Write, times: &FileTimes) -> Result<(), MabiError> {
+    stm.write_u64::<LittleEndian>(times.created)?;
+    stm.write_u64::<LittleEndian>(times.created)?;
+    stm.write_u64::<LittleEndian>(times.accessed)?;
+    stm.write_u64::<LittleEndian>(times.modified)?;
+    stm.write_u64::<LittleEndian>(times.modified)?;
     Ok(())
 }
 
 fn write_file_entry(
     stm: &mut impl Write,
+    name: &[u8],
     ent: &FileInfo,
-    root_dir: &str,
+    times: &FileTimes,
 ) -> Result<u64, MabiError> {
-    let str_block_size = write_str_block(stm, &ent.name)?;
+    let str_block_size = write_str_block(stm, name)?;
     stm.write_u32::<LittleEndian>(ent.version)?;
     stm.write_u32::<LittleEndian>(0)?;
     stm.write_u32::<LittleEndian>(ent.off)?;
     stm.write_u32::<LittleEndian>(ent.raw_size)?;
     stm.write_u32::<LittleEndian>(ent.uncompr_size)?;
-    stm.write_u32::<LittleEndian>(1)?;
-    write_file_time(stm, root_dir, &ent.name)?;
+    stm.write_u32::<LittleEndian>(ent.compression.flag())?;
+    write_file_time(stm, times)?;
     Ok(str_block_size + 0x40)
 }
 
+// The build time, or SOURCE_DATE_EPOCH if set, for reproducible builds
+fn build_time() -> SystemTime {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .map_or_else(SystemTime::now, |secs| {
+            UNIX_EPOCH + std::time::Duration::from_secs(secs)
+        })
+}
+
 fn write_header_time(stm: &mut impl Write) -> Result<(), MabiError> {
-    let cur = time_to_filetime(SystemTime::now())?;
+    let cur = time_to_filetime(build_time())?;
     stm.write_u64::<LittleEndian>(cur)?;
     stm.write_u64::<LittleEndian>(cur)?;
     Ok(())
 }
 
-fn write_header(stm: &mut impl Write, head_info: &HeadInfo) -> Result<(), MabiError> {
+fn write_header(
+    stm: &mut impl Write,
+    head_info: &HeadInfo,
+    root_name: &str,
+) -> Result<(), MabiError> {
     stm.write_u32::<LittleEndian>(0x4b434150)?;
     stm.write_u32::<LittleEndian>(0x102)?;
     stm.write_u32::<LittleEndian>(head_info.file_ver)?;
     stm.write_u32::<LittleEndian>(head_info.file_cnt)?;
     write_header_time(stm)?;
-    stm.write("data\\".as_bytes())?;
-    stm.write(&[0; 0x1e0 - 5])?;
+    // keep at least one null terminator in the field
+    let root_name = &root_name.as_bytes()[..root_name.len().min(ROOT_NAME_SIZE - 1)];
+    stm.write_all(root_name)?;
+    stm.write_all(&vec![0; ROOT_NAME_SIZE - root_name.len()])?;
     stm.write_u32::<LittleEndian>(head_info.file_cnt)?;
     stm.write_u32::<LittleEndian>(head_info.index_size)?;
     stm.write_u32::<LittleEndian>(0)?;
     stm.write_u32::<LittleEndian>(head_info.content_size)?;
-    stm.write_all(&[0; 16])?;
+    stm.write_all(&vec![0; (head_info.header_size - MIN_HEADER_SIZE) as usize])?;
     Ok(())
 }
 
-pub fn run_pack(input_folder: &str, output_fname: &str, version: &str) -> Result<(), MabiError> {
-    let version = version
-        .parse::<u32>()
-        .map_err(|_| MabiError::InvalidVersion)?;
-    let file_names: Vec<String> = WalkDir::new(input_folder)
+#[cfg(windows)]
+fn has_hidden_attribute(e: &DirEntry) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    e.metadata()
+        .is_ok_and(|m| m.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+#[cfg(not(windows))]
+fn has_hidden_attribute(_e: &DirEntry) -> bool {
+    false
+}
+
+fn is_hidden(e: &DirEntry) -> bool {
+    e.file_name().to_str().is_some_and(|n| n.starts_with('.')) || has_hidden_attribute(e)
+}
+
+// Whether a folder was already walked, e.g. through a link to a parent folder or
+// a second link to the same folder, which would loop or pack it twice.
+fn is_visited(visited: &mut HashSet<PathBuf>, e: &DirEntry) -> bool {
+    if !e.file_type().is_dir() {
+        return false;
+    }
+    let Ok(path) = e.path().canonicalize() else {
+        return false;
+    };
+    if visited.insert(path) {
+        return false;
+    }
+    info!(
+        "warning: {} was already packed, skipped",
+        e.path().display()
+    );
+    true
+}
+
+fn list_folder(input_folder: &str, options: &PackOptions) -> Result<Vec<String>, MabiError> {
+    let mut visited = HashSet::new();
+    WalkDir::new(input_folder)
+        .follow_links(!options.skip_links)
+        // so that a folder is packed under its own path rather than a link's
+        .sort_by(|a, b| a.path_is_symlink().cmp(&b.path_is_symlink()))
         .into_iter()
+        // the input folder itself may well be `.`
+        .filter_entry(|e| {
+            let skipped =
+                options.skip_hidden && is_hidden(e) || options.skip_links && e.path_is_symlink();
+            (e.depth() == 0 || !skipped) && !is_visited(&mut visited, e)
+        })
         .filter_map(|e| e.ok())
-        .filter(|e| !e.file_type().is_dir())
-        .map(|e| get_rel_path(input_folder, e.into_path().to_str().unwrap()))
+        .filter(|e| e.depth() > 0)
+        .filter(|e| !e.file_type().is_dir() || options.record_empty_dirs && is_empty_dir(e.path()))
+        .map(|e| {
+            let is_dir = e.file_type().is_dir();
+            let rel_path = get_rel_path(input_folder, e.into_path().to_str().unwrap())?;
+            Ok(if is_dir {
+                rel_path + MAIN_SEPARATOR_STR
+            } else {
+                rel_path
+            })
+        })
         .collect::<Result<Vec<String>, MabiError>>()
-        .map_err(|e| MabiError::TraversingFail(e.to_string()))?;
+        .map_err(|e| MabiError::TraversingFail(e.to_string()))
+}
 
-    let index_size: u64 = file_names
-        .iter()
-        .map(|s| calc_str_size(s.as_bytes().len()).0 + 0x40)
-        .sum::<usize>() as u64;
+/// A file to pack, with its version and, from a list, its own compression.
+struct PackItem {
+    name: String,
+    version: u32,
+    compression: Option<Compression>,
+}
+
+// `store` and `compress` read better in a list than the compression names.
+fn parse_item_compression(s: &str) -> Result<Option<Compression>, MabiError> {
+    match s {
+        "" | "compress" => Ok(None),
+        "store" => Ok(Some(Compression::Stored)),
+        s => s.parse().map(Some),
+    }
+}
+
+/// Reads the files to pack from a list, one file per line. A line is either the
+/// name of the file, or its version followed by a space and its name like
+/// printed by `list --with-version`. Files without a version get `default_version`.
+///
+/// A line containing a tab is instead made of tab-separated fields: the name,
+/// then optionally the version and the compression of the file, `store`,
+/// `compress` or a compression name. Empty fields get the default.
+fn read_file_list(list: &str, default_version: u32) -> Result<Vec<PackItem>, MabiError> {
+    let text = std::fs::read_to_string(list)?;
+    text.lines()
+        .map(|l| l.trim_end_matches('\r'))
+        .filter(|l| !l.is_empty())
+        .map(|l| {
+            let (version, name, compression) = if l.contains('\t') {
+                let mut fields = l.split('\t');
+                let name = fields.next().unwrap_or_default();
+                let version = match fields.next().unwrap_or_default() {
+                    "" => default_version,
+                    v => parse_version(v).ok_or(MabiError::InvalidVersion)?,
+                };
+                let compression = parse_item_compression(fields.next().unwrap_or_default())?;
+                (version, name, compression)
+            } else {
+                match l.split_once(' ') {
+                    Some((v, name)) if !name.is_empty() => match parse_version(v) {
+                        Some(v) => (v, name, None),
+                        None => (default_version, l, None),
+                    },
+                    _ => (default_version, l, None),
+                }
+            };
+            Ok(PackItem {
+                name: name.replace('\\', MAIN_SEPARATOR_STR),
+                version,
+                compression,
+            })
+        })
+        .collect()
+}
+
+/// Refuses an output inside the input folder, as it would be packed into itself.
+fn check_output_outside_input(input_folder: &str, output_fname: &str) -> Result<(), MabiError> {
+    let input = Path::new(input_folder).canonicalize()?;
+    let output = Path::new(output_fname);
+    let output_dir = match output.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    // the output itself may not exist yet, but its folder must
+    let output = output_dir
+        .canonicalize()?
+        .join(output.file_name().unwrap_or_default());
+    if output.starts_with(&input) {
+        return Err(MabiError::OutputInsideInput(output_fname.to_string()));
+    }
+    Ok(())
+}
+
+pub fn run_pack(
+    input_folder: &str,
+    output_fname: &str,
+    version: &str,
+    options: &PackOptions,
+) -> Result<(), MabiError> {
+    let version = parse_version(version).ok_or(MabiError::InvalidVersion)?;
+    if !Path::new(input_folder).is_dir() {
+        return Err(MabiError::NotAFolder(input_folder.to_string()));
+    }
+    if let Some(header_size) = options.header_size {
+        check_header_size(header_size)?;
+    }
+    if options.dedupe && options.seed_derivation == SeedDerivation::Name {
+        return Err(MabiError::InvalidArgument(
+            "files sharing content can't have key streams seeded from their names".to_string(),
+        ));
+    }
+    if output_fname != STDOUT_NAME {
+        check_output_outside_input(input_folder, output_fname)?;
+    }
+    let mut file_names: Vec<PackItem> = match &options.from_list {
+        Some(list) => {
+            let mut file_names = read_file_list(list, version)?;
+            if options.on_missing == OnMissing::Skip {
+                file_names.retain(|item| {
+                    let exists = Path::new(input_folder).join(&item.name).exists();
+                    if !exists {
+                        info!("skipping missing file: {}", item.name);
+                    }
+                    exists
+                });
+            }
+            file_names
+        }
+        None => list_folder(input_folder, options)?
+            .into_iter()
+            .map(|name| PackItem {
+                name,
+                version,
+                compression: None,
+            })
+            .collect(),
+    };
+    if options.skip_empty || options.warn_empty {
+        file_names.retain(|item| {
+            let empty = !item.name.ends_with(MAIN_SEPARATOR)
+                && metadata(Path::new(input_folder).join(&item.name)).is_ok_and(|m| m.len() == 0);
+            if empty && options.warn_empty {
+                if options.skip_empty {
+                    info!("skipping empty file: {}", item.name);
+                } else {
+                    info!("warning: empty file: {}", item.name);
+                }
+            }
+            !(empty && options.skip_empty)
+        });
+    }
+    if options.validate_names {
+        validate_names(&file_names, options.encoding)?;
+    }
+
+    if output_fname == STDOUT_NAME {
+        if options.report_json.is_some() {
+            return Err(MabiError::InvalidArgument(
+                "a report can't be written for a pack written to stdout".to_string(),
+            ));
+        }
+        if options.verify.is_some() {
+            return Err(MabiError::InvalidArgument(
+                "a pack written to stdout can't be verified".to_string(),
+            ));
+        }
+        return pack_to_stdout(input_folder, &file_names, version, options);
+    }
+
+    // write to a temporary file first, so that the output only ever holds a
+    // complete pack, even if packing fails or is interrupted
+    let tmp_fname = temp_output(output_fname, options);
+    let ret = create_output(&tmp_fname).and_then(|fs| {
+        let mut stm = HashWriter::new(BufWriter::new(fs));
+        let content_dir = content_dir(output_fname, options);
+        let (size, skipped) = write_pack(
+            &mut stm,
+            &content_dir,
+            input_folder,
+            &file_names,
+            version,
+            options,
+        )?;
+        let (stm, hash) = finish_pack(stm, size, output_fname, options)?;
+        commit_output(stm, &tmp_fname, output_fname, options)?;
+        Ok((skipped, hash))
+    });
+    let (skipped, file_hash) = match ret {
+        Ok(ret) => ret,
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp_fname);
+            return Err(e);
+        }
+    };
+    let read_options = ReadOptions {
+        encoding: options.encoding,
+        key_generator: options.key_generator,
+        seed_derivation: options.seed_derivation,
+        header_size: options.header_size.unwrap_or(HEADER_SIZE),
+        ..Default::default()
+    };
+    if let Some(scope) = options.verify {
+        let sources: Vec<&str> = file_names
+            .iter()
+            .map(|item| item.name.as_str())
+            .filter(|name| !skipped.iter().any(|s| s == name))
+            .collect();
+        let full = scope == VerifyScope::All;
+        verify_built_pack(
+            output_fname,
+            input_folder,
+            &sources,
+            &read_options,
+            &options.text_eol,
+            full,
+        )?;
+    }
+    if let Some(report_fname) = &options.report_json {
+        write_report(output_fname, report_fname, &read_options, &file_hash)?;
+    }
+    Ok(())
+}
+
+// The temporary file of a pack for `output_fname`, next to it or in `temp_dir`.
+fn temp_output(output_fname: &str, options: &PackOptions) -> PathBuf {
+    match &options.temp_dir {
+        Some(dir) => {
+            let name = Path::new(output_fname).file_name().unwrap_or_default();
+            Path::new(dir).join(format!("{}.tmp", name.to_string_lossy()))
+        }
+        None => PathBuf::from(format!("{}.tmp", output_fname)),
+    }
+}
+
+// Where the content goes until the header and the index before it are known:
+// `temp_dir`, else next to the output, or in the temporary folder of the
+// system for stdout.
+fn content_dir(output_fname: &str, options: &PackOptions) -> PathBuf {
+    match &options.temp_dir {
+        Some(dir) => PathBuf::from(dir),
+        None if output_fname == STDOUT_NAME => std::env::temp_dir(),
+        None => match Path::new(output_fname).parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        },
+    }
+}
 
-    let fs = OpenOptions::new()
+fn create_output(tmp_fname: &Path) -> Result<File, MabiError> {
+    Ok(OpenOptions::new()
         .create(true)
         .write(true)
-        .open(output_fname)?;
-    let mut stm = BufWriter::new(fs);
-    stm.write_all(&[0; HEADER_SIZE as usize])?;
-    let content_start_off = HEADER_SIZE + index_size;
+        .truncate(true)
+        .open(tmp_fname)?)
+}
+
+// Pads the pack of `size` bytes written through `stm` and appends its trailer
+// as asked. Returns the output, and the sha256 of all the bytes written.
+fn finish_pack<W: Write>(
+    mut stm: HashWriter<W>,
+    size: u64,
+    output_fname: &str,
+    options: &PackOptions,
+) -> Result<(W, Vec<u8>), MabiError> {
+    if let Some(pad_to) = options.pad_to {
+        let trailer_size = if options.with_trailer {
+            TRAILER_SIZE
+        } else {
+            0
+        };
+        let pad_to = pad_to.saturating_sub(trailer_size);
+        if size > pad_to {
+            return Err(MabiError::InvalidArgument(format!(
+                "pad to {}: the pack is already {} bytes",
+                pad_to, size
+            )));
+        }
+        io::copy(&mut io::repeat(0).take(pad_to - size), &mut stm)?;
+    }
+    if options.with_trailer {
+        let hash = stm.digest();
+        write_trailer_with_hash(&mut stm, &hash)?;
+    }
+    let (stm, hash) = stm.finish();
+    verbose!("{}: sha256 {}", output_fname, to_hex(&hash));
+    Ok((stm, hash))
+}
+
+// Flushes the temporary file of a complete pack, then moves it to the output.
+fn commit_output(
+    stm: BufWriter<File>,
+    tmp_fname: &Path,
+    output_fname: &str,
+    options: &PackOptions,
+) -> Result<(), MabiError> {
+    let fs = stm.into_inner().map_err(|e| e.into_error())?;
+    if options.sync {
+        fs.sync_all()?;
+    }
+    drop(fs);
+    move_file(tmp_fname, Path::new(output_fname))
+}
+
+/// Builds a pack from a stream of files instead of a folder, e.g. piped from a
+/// program generating them, `-` reading stdin. The stream is a sequence of
+/// records, each made of:
+///
+/// - the size of the name in bytes, u32 little-endian;
+/// - the name in UTF-8, with `\` or `/` as separator;
+/// - the size of the content in bytes, u32 little-endian;
+/// - the content.
+///
+/// The stream ends after the last record. Every file gets `version` and the
+/// build time as its timestamps, and is built in memory with `PackBuilder`, so of the options
+/// only the root name, the compression and those about the output apply.
+pub fn run_pack_from_stream(
+    stream_fname: &str,
+    output_fname: &str,
+    version: &str,
+    options: &PackOptions,
+) -> Result<(), MabiError> {
+    let version = parse_version(version).ok_or(MabiError::InvalidVersion)?;
+    if options.encoding != NameEncoding::Utf8
+        || options.key_generator != KeyGenerator::default()
+        || options.seed_derivation != SeedDerivation::default()
+    {
+        return Err(MabiError::InvalidArgument(
+            "a pack built from a stream has utf8 names and the default key stream".to_string(),
+        ));
+    }
+    let mut builder = PackBuilder::new(version);
+    if let Some(root_name) = &options.root_name {
+        builder.set_root_name(root_name);
+    }
+    builder.set_compression(options.compression);
+    let stm: Box<dyn Read> = if stream_fname == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(File::open(stream_fname)?)
+    };
+    let mut stm = io::BufReader::new(stm);
+    let cur = time_to_filetime(build_time())?;
+    let times = FileTimes {
+        created: cur,
+        accessed: cur,
+        modified: cur,
+    };
+    let mut count = 0;
+    while let Some((name, content)) = read_stream_record(&mut stm, count + 1)? {
+        check_interrupted(&options.cancel)?;
+        verbose!("{}: {} bytes", name, content.len());
+        builder.add_bytes_with_times(&name, version, &content, times)?;
+        count += 1;
+    }
+    let image = builder.finish()?;
+    verbose!("{} files read from the stream", count);
+
+    if output_fname == STDOUT_NAME {
+        if options.with_trailer || options.pad_to.is_some() {
+            return Err(MabiError::InvalidArgument(
+                "a pack built from a stream is written to stdout as is".to_string(),
+            ));
+        }
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&image)?;
+        return Ok(stdout.flush()?);
+    }
+    let tmp_fname = temp_output(output_fname, options);
+    let ret = create_output(&tmp_fname).and_then(|fs| {
+        let mut stm = HashWriter::new(BufWriter::new(fs));
+        stm.write_all(&image)?;
+        let (stm, _) = finish_pack(stm, image.len() as u64, output_fname, options)?;
+        commit_output(stm, &tmp_fname, output_fname, options)
+    });
+    if ret.is_err() {
+        let _ = std::fs::remove_file(&tmp_fname);
+    }
+    ret
+}
+
+// Reads the next record of a stream for `run_pack_from_stream`, or None at its
+// end. A stream ending inside a record fails, naming the record and the part
+// cut short.
+fn read_stream_record(
+    stm: &mut impl Read,
+    record: usize,
+) -> Result<Option<(String, Vec<u8>)>, MabiError> {
+    let truncated = |part: &str| {
+        MabiError::InvalidArgument(format!(
+            "stream truncated in the {} of record {}",
+            part, record
+        ))
+    };
+    let mut size = [0; 4];
+    let mut read = 0;
+    while read < size.len() {
+        match stm.read(&mut size[read..])? {
+            0 if read == 0 => return Ok(None),
+            0 => return Err(truncated("name size")),
+            n => read += n,
+        }
+    }
+    let name = read_part(stm, u32::from_le_bytes(size))?.ok_or_else(|| truncated("name"))?;
+    let name = String::from_utf8(name)
+        .map_err(|_| MabiError::InvalidArgument(format!("name of record {} not UTF-8", record)))?
+        .replace('/', "\\");
+    let size = read_part(stm, 4)?.ok_or_else(|| truncated("content size"))?;
+    let len = u32::from_le_bytes([size[0], size[1], size[2], size[3]]);
+    let content = read_part(stm, len)?.ok_or_else(|| truncated("content"))?;
+    Ok(Some((name, content)))
+}
+
+// Reads `len` bytes, or None if the stream ends before.
+fn read_part(stm: &mut impl Read, len: u32) -> io::Result<Option<Vec<u8>>> {
+    let mut buff = vec![];
+    let read = stm.take(len as u64).read_to_end(&mut buff)?;
+    Ok((read == len as usize).then_some(buff))
+}
 
+// The names as stored in the index, failing on a name the encoding can't
+// represent, or an index too large for the header, before anything is written.
+fn encode_names(
+    file_names: &[PackItem],
+    encoding: NameEncoding,
+) -> Result<Vec<Vec<u8>>, MabiError> {
+    let encoded_names = file_names
+        .iter()
+        .map(|item| encode_name(&item.name, encoding))
+        .collect::<Result<Vec<_>, MabiError>>()?;
+    checked_index_size(&encoded_names)?;
+    Ok(encoded_names)
+}
+
+fn calc_index_size(encoded_names: &[Vec<u8>]) -> u64 {
+    encoded_names
+        .iter()
+        .map(|s| (calc_str_size(s.len()).0 + 0x40) as u64)
+        .sum()
+}
+
+// The index size as stored in the header, which is a u32 too
+fn checked_index_size(encoded_names: &[Vec<u8>]) -> Result<u32, MabiError> {
+    let index_size = calc_index_size(encoded_names);
+    u32::try_from(index_size).map_err(|_| MabiError::PackTooLarge(index_size))
+}
+
+// The format has no 64-bit variant: offsets and sizes in the index and the
+// header are u32, so the content must end within 4GB.
+fn checked_content_end(off: u32, raw_size: u32) -> Result<u32, MabiError> {
+    off.checked_add(raw_size)
+        .ok_or(MabiError::PackTooLarge(off as u64 + raw_size as u64))
+}
+
+/// Compresses and encrypts the files one after the other. Returns their index
+/// entries, with offsets from the start of the content, and the content size.
+fn write_contents(
+    stm: &mut impl Write,
+    input_folder: &str,
+    file_names: &[PackItem],
+    options: &PackOptions,
+) -> Result<(Vec<(FileInfo, FileTimes)>, u32), MabiError> {
     let mut content_off = 0;
-    let mut index_off = HEADER_SIZE;
-    for name in &file_names {
-        let (mut fi, packed_file) = pack_file(input_folder, &name, version)
-            .map_err(|e| MabiError::PackingFail(name.clone(), e.to_string()))?;
-        fi.off = content_off;
-        stm.seek(SeekFrom::Start(index_off))?;
-        index_off += write_file_entry(&mut stm, &fi, input_folder)?;
-        stm.seek(SeekFrom::Start(content_start_off + content_off as u64))?;
-        stm.write_all(&packed_file)?;
-        content_off += fi.raw_size;
-    }
-
-    let file_size = stm.seek(SeekFrom::Current(0))?;
-    stm.seek(SeekFrom::Start(0))?;
+    let mut dedupe = ContentDedupe::default();
+    let mut entries = Vec::with_capacity(file_names.len());
+    let mut skipped = vec![];
+    let mut progress = Progress::new(options.progress_json, file_names.len());
+    for item in file_names {
+        let (name, version) = (&item.name, item.version);
+        let compression = item.compression.unwrap_or(options.compression);
+        check_interrupted(&options.cancel)?;
+        progress.next(name);
+        // read first, so that a file left out has nothing written yet
+        let times = read_file_times(input_folder, name, options.times_fallback);
+        let Some(times) = skip_unreadable(times, name, options, &mut skipped)? else {
+            continue;
+        };
+        let fi = if name.ends_with(MAIN_SEPARATOR) {
+            FileInfo {
+                name: name.clone(),
+                version,
+                off: content_off,
+                raw_size: 0,
+                uncompr_size: 0,
+                compression,
+                created: 0,
+                accessed: 0,
+                modified: 0,
+            }
+        } else {
+            let content = read_file(input_folder, name, &options.text_eol);
+            let Some(content) = skip_unreadable(content, name, options, &mut skipped)? else {
+                continue;
+            };
+            progress.set_bytes(content.len() as u64);
+            let shared = if options.dedupe {
+                dedupe
+                    .find(input_folder, &options.text_eol, &content, version)
+                    .map_err(|e| MabiError::PackingFail(name.clone(), e.to_string()))?
+            } else {
+                None
+            };
+            match shared {
+                Some((off, raw_size, compression)) => FileInfo {
+                    name: name.clone(),
+                    version,
+                    off,
+                    raw_size,
+                    uncompr_size: content.len() as u32,
+                    compression,
+                    created: 0,
+                    accessed: 0,
+                    modified: 0,
+                },
+                None => {
+                    let seed = options.seed_derivation.seed(
+                        version,
+                        &name.replace(MAIN_SEPARATOR, "\\"),
+                        content_off,
+                    );
+                    let (mut fi, packed_file) = pack_file(
+                        name,
+                        &content,
+                        version,
+                        compression,
+                        options.store_incompressible,
+                        options.key_generator,
+                        seed,
+                    )
+                    .map_err(|e| MabiError::PackingFail(name.clone(), e.to_string()))?;
+                    fi.off = content_off;
+                    content_off = checked_content_end(content_off, fi.raw_size)?;
+                    stm.write_all(&packed_file)?;
+                    if options.dedupe {
+                        dedupe.insert(&content, version, name, &fi);
+                    }
+                    fi
+                }
+            }
+        };
+        entries.push(timed_entry(fi, times));
+    }
+    progress.finish();
+    if !skipped.is_empty() {
+        info!(
+            "warning: {} unreadable files left out: {}",
+            skipped.len(),
+            skipped.join(", ")
+        );
+    }
+    Ok((entries, content_off))
+}
+
+// Fails packing if a file can't be read, or with `continue_on_error` returns
+// None to leave it out.
+fn skip_unreadable<T>(
+    ret: Result<T, MabiError>,
+    name: &str,
+    options: &PackOptions,
+    skipped: &mut Vec<String>,
+) -> Result<Option<T>, MabiError> {
+    match ret {
+        Ok(v) => Ok(Some(v)),
+        Err(e) if options.continue_on_error => {
+            info!("warning: can't read {}, skipped: {}", name, e);
+            skipped.push(name.to_string());
+            Ok(None)
+        }
+        Err(e) => Err(MabiError::PackingFail(name.to_string(), e.to_string())),
+    }
+}
+
+// The names of the files actually packed, fewer than listed if some were left
+// out by `continue_on_error`.
+fn packed_names(
+    entries: &[(FileInfo, FileTimes)],
+    file_names: &[PackItem],
+    encoded_names: Vec<Vec<u8>>,
+    encoding: NameEncoding,
+) -> Result<Vec<Vec<u8>>, MabiError> {
+    if entries.len() == file_names.len() {
+        return Ok(encoded_names);
+    }
+    entries
+        .iter()
+        .map(|(fi, _)| encode_name(&fi.name, encoding))
+        .collect()
+}
+
+fn write_header_and_index(
+    stm: &mut impl Write,
+    file_ver: u32,
+    entries: &[(FileInfo, FileTimes)],
+    encoded_names: &[Vec<u8>],
+    content_size: u32,
+    root_name: &str,
+    header_size: u64,
+) -> Result<(), MabiError> {
     write_header(
-        &mut stm,
+        stm,
         &HeadInfo {
-            file_ver: version,
-            file_cnt: file_names.len() as u32,
-            index_size: index_size as u32,
-            content_size: (file_size - content_start_off) as u32,
+            file_ver,
+            file_cnt: entries.len() as u32,
+            index_size: checked_index_size(encoded_names)?,
+            content_size,
+            header_size,
         },
+        root_name,
     )?;
-
+    for ((fi, times), name) in entries.iter().zip(encoded_names) {
+        write_file_entry(stm, name, fi, times)?;
+    }
     Ok(())
 }
+
+fn pack_to_stdout(
+    input_folder: &str,
+    file_names: &[PackItem],
+    file_ver: u32,
+    options: &PackOptions,
+) -> Result<(), MabiError> {
+    let mut stm = HashWriter::new(BufWriter::new(io::stdout().lock()));
+    let content_dir = content_dir(STDOUT_NAME, options);
+    let (size, _) = write_pack(
+        &mut stm,
+        &content_dir,
+        input_folder,
+        file_names,
+        file_ver,
+        options,
+    )?;
+    let (mut stm, _) = finish_pack(stm, size, "stdout", options)?;
+    Ok(stm.flush()?)
+}
+
+/// Writes the pack from start to end, so that it can go to an output which
+/// can't seek like a pipe, and be hashed on the way: the content is written to
+/// a temporary file in `content_dir` first, then copied after the header and
+/// the index. Returns the size of the pack, and the names of the files left
+/// out by `continue_on_error`.
+fn write_pack(
+    stm: &mut impl Write,
+    content_dir: &Path,
+    input_folder: &str,
+    file_names: &[PackItem],
+    file_ver: u32,
+    options: &PackOptions,
+) -> Result<(u64, Vec<String>), MabiError> {
+    let encoded_names = encode_names(file_names, options.encoding)?;
+    let header_size = options.header_size.unwrap_or(HEADER_SIZE);
+
+    let (fs, content_fname) = create_content_file(content_dir)?;
+    let mut content = BufWriter::new(fs);
+    let ret = write_contents(&mut content, input_folder, file_names, options).and_then(
+        |(entries, content_size)| {
+            let mut fs = content.into_inner().map_err(|e| e.into_error())?;
+            fs.seek(SeekFrom::Start(0))?;
+            // the index of the files left out isn't written
+            let encoded_names =
+                packed_names(&entries, file_names, encoded_names, options.encoding)?;
+            write_header_and_index(
+                stm,
+                file_ver,
+                &entries,
+                &encoded_names,
+                content_size,
+                options.root_name.as_deref().unwrap_or(DEFAULT_ROOT_NAME),
+                header_size,
+            )?;
+            io::copy(&mut fs, stm)?;
+            let size = header_size + calc_index_size(&encoded_names) + content_size as u64;
+            let packed: HashSet<&str> = entries.iter().map(|(fi, _)| fi.name.as_str()).collect();
+            let skipped = file_names
+                .iter()
+                .filter(|item| !packed.contains(item.name.as_str()))
+                .map(|item| item.name.clone())
+                .collect();
+            Ok((size, skipped))
+        },
+    );
+    let _ = std::fs::remove_file(&content_fname);
+    ret
+}
+
+// Renames a file, or copies it when it's on another volume.
+fn move_file(from: &Path, to: &Path) -> Result<(), MabiError> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            verbose!("{}: on another volume, copied", from.display());
+            std::fs::copy(from, to)?;
+            std::fs::remove_file(from)?;
+            Ok(())
+        }
+        ret => Ok(ret?),
+    }
+}
+
+// Creates a new file for the content in `dir`, which may be shared, under a
+// random name so that it can't be guessed beforehand, never reusing a file.
+fn create_content_file(dir: &Path) -> io::Result<(File, PathBuf)> {
+    let random = RandomState::new();
+    let mut attempt = 0u32;
+    loop {
+        let suffix = random.hash_one((std::process::id(), attempt, SystemTime::now()));
+        let fname = dir.join(format!("mabi-pack-{:016x}.content", suffix));
+        match OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&fname)
+        {
+            Ok(fs) => return Ok((fs, fname)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Builds a pack entirely in memory, e.g. from generated content.
+pub struct PackBuilder {
+    file_ver: u32,
+    root_name: String,
+    compression: Compression,
+    entries: Vec<(FileInfo, FileTimes)>,
+    content: Vec<u8>,
+}
+
+impl PackBuilder {
+    pub fn new(file_ver: u32) -> PackBuilder {
+        PackBuilder {
+            file_ver,
+            root_name: DEFAULT_ROOT_NAME.to_string(),
+            compression: Compression::Zlib,
+            entries: vec![],
+            content: vec![],
+        }
+    }
+
+    /// Sets the root path embedded in the header, `data\` by default.
+    pub fn set_root_name(&mut self, root_name: &str) {
+        self.root_name = root_name.to_string();
+    }
+
+    /// Sets the compression of the files added afterwards, zlib by default.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Adds a file with all its timestamps set to now. `version` is also used as
+    /// the seed to encrypt the content.
+    pub fn add_bytes(&mut self, name: &str, version: u32, data: &[u8]) -> Result<(), MabiError> {
+        self.add_bytes_with_times(name, version, data, FileTimes::now()?)
+    }
+
+    pub fn add_bytes_with_times(
+        &mut self,
+        name: &str,
+        version: u32,
+        data: &[u8],
+        times: FileTimes,
+    ) -> Result<(), MabiError> {
+        let (mut fi, packed_file) = pack_file(
+            name,
+            data,
+            version,
+            self.compression,
+            None,
+            KeyGenerator::default(),
+            SeedDerivation::Version.seed(version, name, 0),
+        )
+        .map_err(|e| MabiError::PackingFail(name.to_string(), e.to_string()))?;
+        fi.off = self.content_size()?;
+        checked_content_end(fi.off, fi.raw_size)?;
+        self.content.extend_from_slice(&packed_file);
+        self.entries.push(timed_entry(fi, times));
+        Ok(())
+    }
+
+    // The size of the content so far, which must fit the u32 of the format.
+    fn content_size(&self) -> Result<u32, MabiError> {
+        let size = self.content.len() as u64;
+        u32::try_from(size).map_err(|_| MabiError::PackTooLarge(size))
+    }
+
+    /// Returns the complete pack image.
+    pub fn finish(self) -> Result<Vec<u8>, MabiError> {
+        let encoded_names = self
+            .entries
+            .iter()
+            .map(|(fi, _)| encode_name(&fi.name, NameEncoding::Utf8))
+            .collect::<Result<Vec<_>, MabiError>>()?;
+        let index_size = calc_index_size(&encoded_names) as usize;
+        let mut stm = Vec::with_capacity(HEADER_SIZE as usize + index_size + self.content.len());
+        write_header_and_index(
+            &mut stm,
+            self.file_ver,
+            &self.entries,
+            &encoded_names,
+            self.content_size()?,
+            &self.root_name,
+            HEADER_SIZE,
+        )?;
+        stm.extend_from_slice(&self.content);
+        Ok(stm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_folder;
+    use proptest::prelude::*;
+    use sha2::{Digest, Sha256};
+    use std::io::Cursor;
+
+    #[test]
+    fn output_inside_input_is_refused() {
+        let root = temp_folder("inside");
+        let input = root.join("data");
+        std::fs::create_dir(&input).unwrap();
+        std::fs::write(input.join("a.txt"), b"hello").unwrap();
+        let output = input.join("out.pack");
+        let ret = run_pack(
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            "1",
+            &PackOptions::default(),
+        );
+        assert!(matches!(ret, Err(MabiError::OutputInsideInput(_))));
+        assert!(!output.exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn report_reads_with_the_key_stream_of_the_pack() {
+        let root = temp_folder("report");
+        let input = root.join("data");
+        std::fs::create_dir(&input).unwrap();
+        std::fs::write(input.join("a.txt"), b"hello").unwrap();
+        std::fs::write(input.join("b.txt"), b"world").unwrap();
+        let output = root.join("out.pack");
+        let report = root.join("report.json");
+        let options = PackOptions {
+            key_generator: KeyGenerator::Mt19937x64,
+            seed_derivation: SeedDerivation::Offset,
+            report_json: Some(report.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        run_pack(
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            "1",
+            &options,
+        )
+        .unwrap();
+        let report = std::fs::read_to_string(&report).unwrap();
+        let mut hasher = Sha256::new();
+        hasher.update(b"hello");
+        assert!(report.contains(&to_hex(&hasher.finalize())));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn output_in_sibling_with_same_prefix_is_accepted() {
+        let root = temp_folder("sibling");
+        let input = root.join("data");
+        std::fs::create_dir(&input).unwrap();
+        std::fs::write(input.join("a.txt"), b"hello").unwrap();
+        std::fs::create_dir(root.join("data2")).unwrap();
+        let output = root.join("data2").join("out.pack");
+        run_pack(
+            input.to_str().unwrap(),
+            output.to_str().unwrap(),
+            "1",
+            &PackOptions::default(),
+        )
+        .unwrap();
+        assert!(output.exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    // Writes a name as a string block and reads it back, checking that the
+    // block is a multiple of 16 bytes, read to its end.
+    fn round_trip(name: &[u8]) -> Result<(), TestCaseError> {
+        let mut block = vec![];
+        let size = write_str_block(&mut block, name).unwrap();
+        prop_assert_eq!(size, block.len() as u64);
+        prop_assert_eq!(size % 16, 0);
+        let mut stm = Cursor::new(&block);
+        let read = crate::read_str(&mut stm, NameEncoding::Utf8).unwrap();
+        prop_assert_eq!(read.as_bytes(), name);
+        prop_assert_eq!(stm.position(), size);
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn str_block_round_trips(name in prop::collection::vec(0x20u8..0x7f, 0..600)) {
+            round_trip(&name)?;
+        }
+    }
+
+    #[test]
+    fn str_block_round_trips_at_marker_boundaries() {
+        // the largest name of every marker and the next one, then names whose
+        // marker 5 block, with its 6 bytes more, exactly fills 16-byte rows
+        let lens = [14, 15, 30, 31, 46, 47, 62, 63, 94, 95, 106, 122, 250, 1018];
+        for len in lens {
+            round_trip(&vec![b'a'; len]).unwrap();
+            round_trip(&vec![b'a'; len + 1]).unwrap();
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use super::json::{null_or, quote, Object};
+use std::time::{Duration, Instant};
+
+/// How many files are processed between two progress reports, unless a second
+/// passes first.
+const PROGRESS_INTERVAL: usize = 100;
+const PROGRESS_PERIOD: Duration = Duration::from_secs(1);
+
+/// Reports the progress of a pack or an extraction on stderr as one JSON object
+/// per line, e.g. for a GUI to drive a progress bar:
+///
+/// `{"done": 100, "total": 2500, "bytes_done": 1234567, "current_name": "db\\itemdb.xml"}`
+///
+/// `done` and `bytes_done` count the files finished, and `current_name` is the
+/// file being processed, or `null` in the last report once all are done.
+pub(crate) struct Progress {
+    enabled: bool,
+    total: usize,
+    done: usize,
+    bytes_done: u64,
+    current: Option<(String, u64)>,
+    last_report: Option<Instant>,
+}
+
+impl Progress {
+    pub(crate) fn new(enabled: bool, total: usize) -> Progress {
+        Progress {
+            enabled,
+            total,
+            done: 0,
+            bytes_done: 0,
+            current: None,
+            last_report: None,
+        }
+    }
+
+    // counts the current file as finished
+    fn finish_current(&mut self) {
+        if let Some((_, bytes)) = self.current.take() {
+            self.done += 1;
+            self.bytes_done += bytes;
+        }
+    }
+
+    /// Starts processing the next file, finishing the previous one.
+    pub(crate) fn next(&mut self, name: &str) {
+        if !self.enabled {
+            return;
+        }
+        self.finish_current();
+        self.current = Some((name.to_string(), 0));
+        let due = match self.last_report {
+            None => true,
+            Some(last) => {
+                self.done.is_multiple_of(PROGRESS_INTERVAL) || last.elapsed() >= PROGRESS_PERIOD
+            }
+        };
+        if due {
+            self.report();
+        }
+    }
+
+    /// Sets the size of the file being processed, counted once it's finished.
+    pub(crate) fn set_bytes(&mut self, bytes: u64) {
+        if let Some((_, current_bytes)) = self.current.as_mut() {
+            *current_bytes = bytes;
+        }
+    }
+
+    /// Finishes the last file, and reports that all are done.
+    pub(crate) fn finish(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        self.finish_current();
+        self.report();
+    }
+
+    fn report(&mut self) {
+        let report = Object::new()
+            .value("done", self.done)
+            .value("total", self.total)
+            .value("bytes_done", self.bytes_done)
+            .value(
+                "current_name",
+                null_or(self.current.as_ref().map(|(name, _)| quote(name))),
+            );
+        eprintln!("{}", report);
+        self.last_report = Some(Instant::now());
+    }
+}
@@ -0,0 +1,91 @@
+use super::{read_entry, MabiError, NameEncoding, HEADER_SIZE};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Seek, SeekFrom, Write};
+
+const FILE_CNT_OFF: u64 = 0xc;
+const FILE_CNT2_OFF: u64 = 0x200;
+const INDEX_SIZE_OFF: u64 = 0x204;
+const CONTENT_SIZE_OFF: u64 = 0x20c;
+
+struct ScanResult {
+    file_cnt: u32,
+    index_size: u32,
+    content_size: u32,
+}
+
+// An entry read from garbage usually has an odd name or points out of the file.
+fn is_plausible(name: &str, off: u32, raw_size: u32, remaining: u64) -> bool {
+    !name.is_empty()
+        && !name.chars().any(|c| c.is_control())
+        && off as u64 + raw_size as u64 <= remaining
+}
+
+/// Walks the index region entry by entry, following the string block + 0x40
+/// layout, until something that can't be an entry is met.
+fn scan_index(stm: &mut BufReader<File>, file_len: u64) -> Result<ScanResult, MabiError> {
+    stm.seek(SeekFrom::Start(HEADER_SIZE))?;
+    let mut file_cnt = 0u32;
+    let mut index_end = HEADER_SIZE;
+    let mut content_end = 0u64;
+    while let Ok(fi) = read_entry(stm, NameEncoding::default()) {
+        let pos = stm.stream_position()?;
+        if pos > file_len || !is_plausible(&fi.name, fi.off, fi.raw_size, file_len - pos) {
+            break;
+        }
+        file_cnt += 1;
+        index_end = pos;
+        content_end = content_end.max(fi.off as u64 + fi.raw_size as u64);
+    }
+    if file_cnt == 0 {
+        return Err(MabiError::ReadIndexFail(
+            "no valid entry found in the index".to_string(),
+        ));
+    }
+    Ok(ScanResult {
+        file_cnt,
+        index_size: (index_end - HEADER_SIZE) as u32,
+        content_size: content_end as u32,
+    })
+}
+
+/// Rebuilds the entry count and the sizes in the header of a damaged pack from
+/// its index. The pack is fixed in place unless `output` is set.
+pub fn run_repair(fname: &str, output: Option<&str>) -> Result<(), MabiError> {
+    let fs = File::open(fname)?;
+    let file_len = fs.metadata()?.len();
+    let mut reader = BufReader::new(fs);
+    if file_len < HEADER_SIZE
+        || reader.read_u32::<LittleEndian>()? != 0x4b434150
+        || reader.read_u32::<LittleEndian>()? != 0x102
+    {
+        return Err(MabiError::WrongFormat);
+    }
+    let scan = scan_index(&mut reader, file_len)?;
+    drop(reader);
+
+    let target = match output {
+        Some(output) => {
+            std::fs::copy(fname, output)?;
+            output
+        }
+        None => fname,
+    };
+    let mut stm = OpenOptions::new().write(true).open(target)?;
+    for (off, value) in [
+        (FILE_CNT_OFF, scan.file_cnt),
+        (FILE_CNT2_OFF, scan.file_cnt),
+        (INDEX_SIZE_OFF, scan.index_size),
+        (CONTENT_SIZE_OFF, scan.content_size),
+    ] {
+        stm.seek(SeekFrom::Start(off))?;
+        stm.write_u32::<LittleEndian>(value)?;
+    }
+    stm.flush()?;
+
+    info!(
+        "{}: {} files, index size 0x{:x}, content size 0x{:x}",
+        target, scan.file_cnt, scan.index_size, scan.content_size
+    );
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use super::extract::{read_file_content_with, ExtractOptions};
+use super::json::Object;
+use super::trailer::to_hex;
+use super::{open_pack, read_header_with, read_index_with, MabiError, ReadOptions};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finalize().to_vec()
+}
+
+/// Writes a JSON report of a built pack: its header fields, `file_hash`, the
+/// sha256 of the whole file hashed as it was written, and every entry of the
+/// index with the sha256 of its decoded content. Entries are in index order, so
+/// the reports of identical packs are identical. The timestamps of the pack are only covered by the hash of the
+/// whole file, so the hashes of the entries still match across rebuilds.
+pub(crate) fn write_report(
+    pack_fname: &str,
+    report_fname: &str,
+    options: &ReadOptions,
+    file_hash: &[u8],
+) -> Result<(), MabiError> {
+    let mut reader = BufReader::new(open_pack(pack_fname)?);
+    let head_info = read_header_with(&mut reader, options)?;
+    let entries = read_index_with(&mut reader, &head_info, options)?;
+    let file_size = reader.seek(SeekFrom::End(0))?;
+    let extract_options = ExtractOptions {
+        read_options: options.clone(),
+        ..Default::default()
+    };
+
+    let mut out = BufWriter::new(File::create(report_fname)?);
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"version\": {},", head_info.file_ver)?;
+    writeln!(out, "  \"files\": {},", head_info.file_cnt)?;
+    writeln!(out, "  \"index_size\": {},", head_info.index_size)?;
+    writeln!(out, "  \"content_size\": {},", head_info.content_size)?;
+    writeln!(out, "  \"file_size\": {},", file_size)?;
+    writeln!(out, "  \"sha256\": \"{}\",", to_hex(file_hash))?;
+    writeln!(out, "  \"entries\": [")?;
+    for (i, fi) in entries.iter().enumerate() {
+        let content = read_file_content_with(&mut reader, &head_info, fi, &extract_options)
+            .map_err(|e| MabiError::ExtractFail(fi.name.clone(), e.to_string()))?;
+        let entry = Object::new()
+            .string("name", &fi.name)
+            .value("version", fi.version)
+            .value("off", fi.off)
+            .value("raw_size", fi.raw_size)
+            .value("uncompr_size", fi.uncompr_size)
+            .string("compression", &fi.compression.to_string())
+            .string("sha256", &to_hex(&sha256(&content)));
+        writeln!(
+            out,
+            "    {}{}",
+            entry,
+            if i + 1 < entries.len() { "," } else { "" }
+        )?;
+    }
+    writeln!(out, "  ]")?;
+    writeln!(out, "}}")?;
+    Ok(out.flush()?)
+}
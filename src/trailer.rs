@@ -0,0 +1,105 @@
+use super::MabiError;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// The size of the optional trailer appended after the content by
+/// `pack --with-trailer`. Its layout is:
+///
+/// | offset | size | value                                              |
+/// |--------|------|----------------------------------------------------|
+/// | 0x0    | 4    | magic `MPTR`                                       |
+/// | 0x4    | 4    | version of the trailer, 1 (little endian)          |
+/// | 0x8    | 0x20 | sha256 of all the bytes of the file before it      |
+///
+/// The game ignores it, as nothing in the header or the index points past the
+/// content.
+pub const TRAILER_SIZE: u64 = 0x28;
+const TRAILER_MAGIC: &[u8; 4] = b"MPTR";
+pub(crate) const TRAILER_VERSION: u32 = 1;
+
+/// Hashes the first `len` bytes of the stream.
+pub(crate) fn hash_prefix(stm: &mut (impl Read + Seek), len: u64) -> Result<Vec<u8>, MabiError> {
+    stm.seek(SeekFrom::Start(0))?;
+    let mut hasher = Sha256::new();
+    let mut buff = vec![0; 0x10000];
+    let mut left = len;
+    while left > 0 {
+        let chunk = &mut buff[..left.min(0x10000) as usize];
+        stm.read_exact(chunk)?;
+        hasher.update(&chunk);
+        left -= chunk.len() as u64;
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+pub(crate) fn to_hex(hash: &[u8]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A writer computing the sha256 of everything written through it, e.g. to
+/// hash a pack while it's written instead of reading it again.
+pub struct HashWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub fn new(inner: W) -> HashWriter<W> {
+        HashWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Returns the sha256 of the bytes written so far.
+    pub fn digest(&self) -> Vec<u8> {
+        self.hasher.clone().finalize().to_vec()
+    }
+
+    /// Returns the inner writer, and the sha256 of all the bytes written.
+    pub fn finish(self) -> (W, Vec<u8>) {
+        (self.inner, self.hasher.finalize().to_vec())
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes the trailer of a pack whose bytes hash to `hash`.
+pub(crate) fn write_trailer_with_hash(stm: &mut impl Write, hash: &[u8]) -> Result<(), MabiError> {
+    stm.write_all(TRAILER_MAGIC)?;
+    stm.write_u32::<LittleEndian>(TRAILER_VERSION)?;
+    stm.write_all(hash)?;
+    Ok(())
+}
+
+/// Returns the version and the hash stored in the trailer, or `None` if the
+/// pack has no trailer.
+pub(crate) fn read_trailer(
+    stm: &mut (impl Read + Seek),
+) -> Result<Option<(u32, Vec<u8>)>, MabiError> {
+    let len = stm.seek(SeekFrom::End(0))?;
+    if len < TRAILER_SIZE {
+        return Ok(None);
+    }
+    stm.seek(SeekFrom::Start(len - TRAILER_SIZE))?;
+    let mut magic = [0; 4];
+    stm.read_exact(&mut magic)?;
+    if &magic != TRAILER_MAGIC {
+        return Ok(None);
+    }
+    let version = stm.read_u32::<LittleEndian>()?;
+    let mut hash = vec![0; 0x20];
+    stm.read_exact(&mut hash)?;
+    Ok(Some((version, hash)))
+}
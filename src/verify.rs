@@ -0,0 +1,526 @@
+use super::eol::TextEol;
+use super::extract::{read_file_content_with, read_file_range, ExtractOptions};
+use super::json::{array, null_or, quote, Object};
+use super::pack::{name_problem, MAX_NAME_SIZE};
+use super::trailer::{hash_prefix, read_trailer, TRAILER_SIZE, TRAILER_VERSION};
+use super::{
+    open_pack, read_header_with, read_index_with, Compression, FileInfo, HeadInfo, MabiError,
+    NameEncoding, ReadOptions,
+};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, MAIN_SEPARATOR};
+use std::str::FromStr;
+
+/// How `run_verify` prints what it found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerifyFormat {
+    /// One line per problem.
+    #[default]
+    Text,
+    /// A JSON object with the result of every file and a summary, see
+    /// `write_json`.
+    Json,
+}
+
+impl FromStr for VerifyFormat {
+    type Err = MabiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(VerifyFormat::Text),
+            "json" => Ok(VerifyFormat::Json),
+            _ => Err(MabiError::InvalidArgument(format!("format: {}", s))),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct VerifyOptions {
+    /// Only check how the content regions are laid out, without decoding them.
+    pub layout: bool,
+    /// Only check the hash of the trailer written by `pack --with-trailer`.
+    pub fast: bool,
+    /// Only check the header: its two file counts, and the length of the file
+    /// against the sizes it records, without reading the index.
+    pub headers_only: bool,
+    /// Also check the pack against the constraints of a client.
+    pub compat: Option<CompatProfile>,
+    /// Only applies when decoding every file, not with `layout`, `fast` or
+    /// `headers_only`.
+    pub format: VerifyFormat,
+    /// How to read the pack. With `compat`, the names are read in the encoding
+    /// of its profile instead.
+    pub read_options: ReadOptions,
+}
+
+/// What a client is known to load, for `verify --compat`. A pack breaking one of
+/// these may still load, but hasn't been seen to.
+#[derive(Debug, Clone, Copy)]
+pub struct CompatProfile {
+    pub name: &'static str,
+    /// The encoding the client reads the names in.
+    pub encoding: NameEncoding,
+    /// The compressions the client decodes.
+    pub compressions: &'static [Compression],
+    /// The most files the client loads from a pack, if it has a known limit.
+    pub max_entries: Option<u32>,
+    /// The longest name in bytes once encoded, see `pack::MAX_NAME_SIZE`.
+    pub max_name_size: usize,
+}
+
+/// The profiles `verify --compat` knows: `current` for the clients of today,
+/// `old-korea` and `old-japan` for old clients reading names in their
+/// codepage. Only zlib is known to be decoded by the game.
+pub const COMPAT_PROFILES: &[CompatProfile] = &[
+    CompatProfile {
+        name: "current",
+        encoding: NameEncoding::Utf8,
+        compressions: &[Compression::Zlib],
+        max_entries: None,
+        max_name_size: MAX_NAME_SIZE,
+    },
+    CompatProfile {
+        name: "old-korea",
+        encoding: NameEncoding::Cp949,
+        compressions: &[Compression::Zlib],
+        max_entries: None,
+        max_name_size: MAX_NAME_SIZE,
+    },
+    CompatProfile {
+        name: "old-japan",
+        encoding: NameEncoding::ShiftJis,
+        compressions: &[Compression::Zlib],
+        max_entries: None,
+        max_name_size: MAX_NAME_SIZE,
+    },
+];
+
+impl FromStr for CompatProfile {
+    type Err = MabiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        COMPAT_PROFILES
+            .iter()
+            .find(|p| p.name == s)
+            .copied()
+            .ok_or_else(|| MabiError::InvalidArgument(format!("compat profile: {}", s)))
+    }
+}
+
+/// Checks the header and the index against what the client of `profile` loads.
+fn check_compat(
+    head_info: &HeadInfo,
+    file_entries: &[FileInfo],
+    profile: &CompatProfile,
+) -> Vec<String> {
+    let mut problems = vec![];
+    if let Some(max) = profile.max_entries.filter(|&max| head_info.file_cnt > max) {
+        problems.push(format!(
+            "{} files, more than the {} of {}",
+            head_info.file_cnt, max, profile.name
+        ));
+    }
+    for fi in file_entries {
+        if !profile.compressions.contains(&fi.compression) {
+            problems.push(format!(
+                "{}: {} compression, not decoded by {}",
+                fi.name, fi.compression, profile.name
+            ));
+        }
+        let problem = match profile.encoding.encode(&fi.name) {
+            Ok(encoded) => name_problem(&fi.name, encoded.len(), profile.max_name_size),
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(problem) = problem {
+            problems.push(format!("{}: {}", fi.name, problem));
+        }
+    }
+    problems
+}
+
+/// Checks that the content regions tile the content area without overlapping
+/// or leaving gaps. Entries sharing exactly the same region (see
+/// `PackOptions::dedupe`) are fine.
+fn check_layout(head_info: &HeadInfo, file_entries: &[FileInfo]) -> Vec<String> {
+    let mut regions: Vec<&FileInfo> = file_entries.iter().collect();
+    regions.sort_by_key(|e| (e.off, e.raw_size));
+    regions.dedup_by_key(|e| (e.off, e.raw_size));
+
+    let mut problems = vec![];
+    let mut end = 0u64;
+    let mut last_name = "";
+    for e in regions {
+        let off = e.off as u64;
+        if off < end {
+            problems.push(format!(
+                "{} overlaps with {} by {} bytes",
+                e.name,
+                last_name,
+                end - off
+            ));
+        } else if off > end {
+            problems.push(format!(
+                "gap of {} bytes before {} at 0x{:x}",
+                off - end,
+                e.name,
+                end
+            ));
+        }
+        if off + e.raw_size as u64 > end {
+            end = off + e.raw_size as u64;
+            last_name = &e.name;
+        }
+    }
+    let content_size = head_info.content_size as u64;
+    if end < content_size {
+        problems.push(format!(
+            "gap of {} bytes at the end of the content",
+            content_size - end
+        ));
+    } else if end > content_size {
+        problems.push(format!(
+            "{} exceeds the content size by {} bytes",
+            last_name,
+            end - content_size
+        ));
+    }
+    problems
+}
+
+fn check_trailer(reader: &mut BufReader<File>) -> Result<Vec<String>, MabiError> {
+    let problem = match read_trailer(reader)? {
+        None => "no trailer".to_string(),
+        Some((version, _)) if version != TRAILER_VERSION => {
+            format!("unknown trailer version {}", version)
+        }
+        Some((_, hash)) => {
+            let len = reader.seek(SeekFrom::End(0))? - TRAILER_SIZE;
+            if hash_prefix(reader, len)? == hash {
+                return Ok(vec![]);
+            }
+            "the hash of the trailer doesn't match".to_string()
+        }
+    };
+    Ok(vec![problem])
+}
+
+/// Checks that the file is as long as its header says: the header, the index
+/// and the content, then possibly zeros padding it, as `pack --pad-to` does, and
+/// a trailer.
+fn check_length(
+    reader: &mut BufReader<File>,
+    head_info: &HeadInfo,
+    fname: &str,
+) -> Result<Vec<String>, MabiError> {
+    let expected =
+        head_info.header_size + head_info.index_size as u64 + head_info.content_size as u64;
+    let mut len = reader.seek(SeekFrom::End(0))?;
+    if len >= expected + TRAILER_SIZE && read_trailer(reader)?.is_some() {
+        len -= TRAILER_SIZE;
+    }
+    Ok(if len < expected {
+        vec![format!("truncated: {} bytes, {} expected", len, expected)]
+    } else if len > expected && !is_zeros(reader, expected, len - expected)? {
+        vec![format!(
+            "{} bytes after the content, {} expected in all",
+            len - expected,
+            expected
+        )]
+    } else {
+        if len > expected {
+            verbose!("{}: padded with {} zeros", fname, len - expected);
+        }
+        vec![]
+    })
+}
+
+// Whether the `len` bytes of the stream from `off` are all zero.
+fn is_zeros(stm: &mut (impl Read + Seek), off: u64, len: u64) -> Result<bool, MabiError> {
+    stm.seek(SeekFrom::Start(off))?;
+    let mut buff = vec![0; 0x10000];
+    let mut left = len;
+    while left > 0 {
+        let chunk = &mut buff[..left.min(0x10000) as usize];
+        stm.read_exact(chunk)?;
+        if chunk.iter().any(|&b| b != 0) {
+            return Ok(false);
+        }
+        left -= chunk.len() as u64;
+    }
+    Ok(true)
+}
+
+/// The result of decoding a file. The names given by `Display` are part of the
+/// JSON output of `verify`, and kept stable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryStatus {
+    Ok,
+    /// Decodes, but not to the size the index records.
+    SizeMismatch,
+    /// Its content doesn't decode.
+    DecodeError,
+    /// Its content goes past the end of the file.
+    Truncated,
+}
+
+impl EntryStatus {
+    const ALL: [EntryStatus; 4] = [
+        EntryStatus::Ok,
+        EntryStatus::SizeMismatch,
+        EntryStatus::DecodeError,
+        EntryStatus::Truncated,
+    ];
+}
+
+impl fmt::Display for EntryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            EntryStatus::Ok => "ok",
+            EntryStatus::SizeMismatch => "size_mismatch",
+            EntryStatus::DecodeError => "decode_error",
+            EntryStatus::Truncated => "truncated",
+        })
+    }
+}
+
+struct EntryCheck<'a> {
+    fi: &'a FileInfo,
+    status: EntryStatus,
+    /// The decoded size, if it decodes.
+    actual_size: Option<u64>,
+    error: Option<String>,
+}
+
+// Decodes a file up to one byte past its recorded size, enough to tell it's
+// larger without decoding all of a file much larger, e.g. a bomb.
+fn check_entry<'a>(
+    reader: &mut BufReader<File>,
+    head_info: &HeadInfo,
+    fi: &'a FileInfo,
+    file_len: u64,
+    read_options: &ReadOptions,
+) -> EntryCheck<'a> {
+    let end =
+        head_info.header_size + head_info.index_size as u64 + fi.off as u64 + fi.raw_size as u64;
+    let limit = fi.uncompr_size as u64 + 1;
+    let (status, actual_size, error) =
+        match read_file_range(reader, head_info, fi, 0, limit, read_options) {
+            Err(e) if end > file_len => (EntryStatus::Truncated, None, Some(e.to_string())),
+            Err(e) => (EntryStatus::DecodeError, None, Some(e.to_string())),
+            Ok(content) if content.len() == fi.uncompr_size as usize => {
+                (EntryStatus::Ok, Some(content.len() as u64), None)
+            }
+            Ok(content) => (
+                EntryStatus::SizeMismatch,
+                Some(content.len() as u64),
+                Some(MabiError::CorruptedFile.to_string()),
+            ),
+        };
+    EntryCheck {
+        fi,
+        status,
+        actual_size,
+        error,
+    }
+}
+
+fn check_entries<'a>(
+    reader: &mut BufReader<File>,
+    head_info: &HeadInfo,
+    file_entries: &'a [FileInfo],
+    read_options: &ReadOptions,
+) -> Result<Vec<EntryCheck<'a>>, MabiError> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    Ok(file_entries
+        .iter()
+        .map(|fi| check_entry(reader, head_info, fi, file_len, read_options))
+        .collect())
+}
+
+/// Writes the checks of `verify --format json`: `{"files": [...], "summary":
+/// {...}}`, with for every file its `name`, `status` (see `EntryStatus`),
+/// `expected_size`, `actual_size` and `error`, the last two null when they
+/// don't apply. Decoding stops one byte past `expected_size`, so a larger file
+/// has that as `actual_size`. The summary counts the files of every status, and lists the
+/// problems found by `--compat`.
+fn write_json(checks: &[EntryCheck], compat_problems: &[String]) {
+    println!("{{");
+    println!("  \"files\": [");
+    for (n, c) in checks.iter().enumerate() {
+        let file = Object::new()
+            .string("name", &c.fi.name)
+            .string("status", &c.status.to_string())
+            .value("expected_size", c.fi.uncompr_size)
+            .value("actual_size", null_or(c.actual_size))
+            .value("error", null_or(c.error.as_deref().map(quote)));
+        println!(
+            "    {}{}",
+            file,
+            if n + 1 == checks.len() { "" } else { "," }
+        );
+    }
+    println!("  ],");
+    let mut summary = Object::new().value("files", checks.len());
+    for status in EntryStatus::ALL {
+        let count = checks.iter().filter(|c| c.status == status).count();
+        summary = summary.value(&status.to_string(), count);
+    }
+    let summary = summary.value(
+        "compat_problems",
+        array(compat_problems.iter().map(|p| quote(p))),
+    );
+    println!("  \"summary\": {}", summary);
+    println!("}}");
+}
+
+// The source of a file as packed, see `PackOptions::text_eol`.
+fn eol_applied(text_eol: &Option<TextEol>, source: &str, original: Vec<u8>) -> Vec<u8> {
+    match text_eol {
+        Some(text_eol) => text_eol.apply(source, original),
+        None => original,
+    }
+}
+
+/// How many files `pack --verify` checks, spread over the index.
+const BUILT_SAMPLE_SIZE: usize = 32;
+
+/// Checks a pack just built from `sources` (the files in `input_folder`, in
+/// index order) by reading it back: its header and index, then whether a
+/// sample of its files, or all of them with `full`, decode to the bytes of
+/// their source. Problems are printed on stderr.
+pub(crate) fn verify_built_pack(
+    fname: &str,
+    input_folder: &str,
+    sources: &[&str],
+    read_options: &ReadOptions,
+    text_eol: &Option<TextEol>,
+    full: bool,
+) -> Result<(), MabiError> {
+    let mut reader = BufReader::new(open_pack(fname)?);
+    let head_info = read_header_with(&mut reader, read_options)
+        .map_err(|e| MabiError::ReadHeaderFail(e.to_string()))?;
+    let file_entries = read_index_with(&mut reader, &head_info, read_options)?;
+    if file_entries.len() != sources.len() {
+        eprintln!(
+            "{} files in the index, {} packed",
+            file_entries.len(),
+            sources.len()
+        );
+        return Err(MabiError::VerifyFail(1));
+    }
+    let step = if full {
+        1
+    } else {
+        sources.len().div_ceil(BUILT_SAMPLE_SIZE).max(1)
+    };
+    let options = ExtractOptions {
+        read_options: read_options.clone(),
+        ..Default::default()
+    };
+    let mut checked = 0;
+    let mut problems = 0;
+    for (fi, source) in file_entries.iter().zip(sources).step_by(step) {
+        if source.ends_with(MAIN_SEPARATOR) {
+            continue;
+        }
+        checked += 1;
+        let problem = match read_file_content_with(&mut reader, &head_info, fi, &options) {
+            Err(e) => format!("{}", e),
+            Ok(content) => match std::fs::read(Path::new(input_folder).join(source))
+                .map(|original| eol_applied(text_eol, source, original))
+            {
+                Ok(original) if original == content => continue,
+                Ok(_) => "doesn't decode to its source".to_string(),
+                Err(e) => format!("can't read its source: {}", e),
+            },
+        };
+        eprintln!("{}: {}", fi.name, problem);
+        problems += 1;
+    }
+    if problems > 0 {
+        return Err(MabiError::VerifyFail(problems));
+    }
+    let total = sources.len();
+    info!("{}: verified, {} of {} files", fname, checked, total);
+    Ok(())
+}
+
+pub fn run_verify(fname: &str, options: &VerifyOptions) -> Result<(), MabiError> {
+    let fs = open_pack(fname)?;
+    let mut reader = BufReader::new(fs);
+    if options.fast {
+        let problems = check_trailer(&mut reader)?;
+        for p in &problems {
+            println!("{}", p);
+        }
+        if !problems.is_empty() {
+            return Err(MabiError::VerifyFail(problems.len()));
+        }
+        info!("{}: ok, trailer matches", fname);
+        return Ok(());
+    }
+    // read_header already checks that the two file counts agree
+    let head_info = read_header_with(&mut reader, &options.read_options)
+        .map_err(|e| MabiError::ReadHeaderFail(e.to_string()))?;
+    if options.headers_only {
+        let problems = check_length(&mut reader, &head_info, fname)?;
+        for p in &problems {
+            println!("{}", p);
+        }
+        if !problems.is_empty() {
+            return Err(MabiError::VerifyFail(problems.len()));
+        }
+        info!("{}: ok, header matches the file", fname);
+        return Ok(());
+    }
+    // names the client can't read in its encoding fail here
+    let file_entries = match &options.compat {
+        Some(profile) => {
+            let read_options = ReadOptions {
+                encoding: profile.encoding,
+                ..options.read_options.clone()
+            };
+            read_index_with(&mut reader, &head_info, &read_options)?
+        }
+        None => read_index_with(&mut reader, &head_info, &options.read_options)?,
+    };
+
+    let checks = if options.layout {
+        vec![]
+    } else {
+        check_entries(
+            &mut reader,
+            &head_info,
+            &file_entries,
+            &options.read_options,
+        )?
+    };
+    let mut problems = if options.layout {
+        check_layout(&head_info, &file_entries)
+    } else {
+        checks
+            .iter()
+            .filter_map(|c| c.error.as_ref().map(|e| format!("{}: {}", c.fi.name, e)))
+            .collect()
+    };
+    let compat_problems = match &options.compat {
+        Some(profile) => check_compat(&head_info, &file_entries, profile),
+        None => vec![],
+    };
+    if options.format == VerifyFormat::Json {
+        write_json(&checks, &compat_problems);
+    }
+    problems.extend(compat_problems);
+    if options.format == VerifyFormat::Text {
+        for p in &problems {
+            println!("{}", p);
+        }
+    }
+    if !problems.is_empty() {
+        return Err(MabiError::VerifyFail(problems.len()));
+    }
+    info!("{}: ok, {} files", fname, file_entries.len());
+    Ok(())
+}
@@ -0,0 +1,35 @@
+use super::list::read_packs_in_dir;
+use super::{glob_to_regex, MabiError, ReadOptions};
+use regex::Regex;
+use std::io::{self, Write};
+
+#[derive(Default)]
+pub struct WhichOptions {
+    /// Treat the query as a glob pattern instead of a regular expression.
+    pub glob: bool,
+}
+
+/// Finds which packs of a folder contain files matching `query`, printing the
+/// pack, the version and the name of every match.
+pub fn run_which(query: &str, folder: &str, options: &WhichOptions) -> Result<(), MabiError> {
+    let pattern = if options.glob {
+        glob_to_regex(query)
+    } else {
+        query.to_string()
+    };
+    let re = Regex::new(&pattern)
+        .map_err(|e| MabiError::InvalidRegexp(query.to_string() + ":" + &e.to_string()))?;
+
+    let mut output_stream = io::stdout().lock();
+    let mut found = false;
+    for (pack_name, entries) in read_packs_in_dir(folder, &ReadOptions::default())? {
+        for e in entries.iter().filter(|e| re.is_match(&e.name)) {
+            writeln!(output_stream, "{} {} {}", pack_name, e.version, e.name)?;
+            found = true;
+        }
+    }
+    if !found {
+        return Err(MabiError::NotFound(query.to_string()));
+    }
+    Ok(())
+}